@@ -0,0 +1,212 @@
+//! [`ABLoop`] repeats a section of a track on players that can't loop one themselves.
+
+use std::{thread, time::Duration};
+
+use async_std::{channel::{unbounded, Receiver, Sender}, future, task, stream::{Stream, StreamExt}};
+use mpris::{PlaybackStatus, Player};
+
+use crate::{fake_progress::ProgressClone, handle::PlayerHandle, progress::ProgressStream, quirks::NO_TRACK, waker::{waking_channel, WakingReceiver, WakingSender}};
+
+/// Longest time to wait without checking for commands.
+const MAX_WAIT_MS: u32 = 250;
+
+/// How close to B counts as having reached it.
+const POSITION_TOLERANCE: Duration = Duration::from_millis(5);
+
+/// A jump in position larger than this, compared to where the track should be, is a seek.
+const SEEK_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Items of an [`ABLoop`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ABLoopEvent {
+    /// B was reached and the player was sent back to A. `count` is how many times that has
+    /// happened.
+    Looped { count: u32 },
+    /// The section was repeated as many times as asked for. This is the last item.
+    Finished,
+    /// Another track started. This is the last item.
+    TrackChanged,
+    /// The loop was stopped, the player quit, or seeking failed. This is the last item.
+    Cancelled,
+}
+
+enum LoopCommand {
+    SetPoints(Duration, Duration),
+    Stop,
+}
+
+/// Sends the player back to A every time playback crosses B. The position is followed from a
+/// [`ProgressStream`] with the position fallback, interpolated between its updates, so rate
+/// changes and pausing are picked up without polling. Seeking by hand is fine: seeking inside
+/// the section or before it keeps the loop going, and seeking past B leaves playback alone until
+/// the position is back before B. As a stream it reports [`ABLoopEvent`]s. Makes a new thread
+/// that runs until the loop ends. Clones control the same loop.
+#[derive(Debug, Clone)]
+pub struct ABLoop {
+    commands: Sender<LoopCommand>,
+    reciever: WakingReceiver<ABLoopEvent>,
+}
+
+impl ABLoop {
+    /// Starts looping between `a` and `b` of the current track until stopped or the track
+    /// changes. The points are swapped if `b` comes before `a`.
+    pub fn start(handle: &PlayerHandle, a: Duration, b: Duration) -> Self {
+        return ABLoop::spawn(handle, a, b, None);
+    }
+
+    /// Same as [`start`](Self::start), but lets playback carry on past B after looping
+    /// `repeats` times.
+    pub fn with_repeats(handle: &PlayerHandle, a: Duration, b: Duration, repeats: u32) -> Self {
+        return ABLoop::spawn(handle, a, b, Some(repeats));
+    }
+
+    fn spawn(handle: &PlayerHandle, a: Duration, b: Duration, repeats: Option<u32>) -> Self {
+        let (commands, command_reciever) = unbounded();
+        let (sender, reciever) = waking_channel();
+        let listener = LoopListener { handle: handle.clone(), commands: command_reciever, sender, section: ordered(a, b), repeats, count: 0 };
+        thread::spawn(move || listener.run());
+        return ABLoop { commands, reciever };
+    }
+
+    /// Moves the loop to a new section. The repeat count carries on.
+    pub fn set_points(&self, a: Duration, b: Duration) {
+        let _ = self.commands.try_send(LoopCommand::SetPoints(a, b));
+    }
+
+    /// Stops looping and lets playback carry on.
+    pub fn stop(&self) {
+        let _ = self.commands.try_send(LoopCommand::Stop);
+    }
+
+    /// Access to the reciever used to send events around.
+    pub fn get_reciever(&self) -> Receiver<ABLoopEvent> {
+        self.reciever.reciever()
+    }
+}
+
+fn ordered(a: Duration, b: Duration) -> (Duration, Duration) {
+    if b < a {
+        return (b, a);
+    }
+    return (a, b);
+}
+
+struct LoopListener {
+    handle: PlayerHandle,
+    commands: Receiver<LoopCommand>,
+    sender: WakingSender<ABLoopEvent>,
+    section: (Duration, Duration),
+    repeats: Option<u32>,
+    count: u32,
+}
+
+impl LoopListener {
+    fn run(mut self) {
+        let event = self.follow();
+        self.sender.send(event);
+        self.sender.close();
+    }
+
+    /// Runs until the loop ends, and returns the last event.
+    fn follow(&mut self) -> ABLoopEvent {
+        let player = match self.handle.find() {
+            Ok(x) => x,
+            Err(_) => return ABLoopEvent::Cancelled,
+        };
+        // Start following before reading, so no change is missed in between
+        let mut updates = ProgressStream::with_position_fallback(&player, MAX_WAIT_MS, self.handle.bus().clone());
+        let mut progress = match ProgressClone::read(&player) {
+            Ok(x) => x,
+            Err(_) => return ABLoopEvent::Cancelled,
+        };
+        let track = progress.track_key();
+        // Starting past B is the same as having seeked there
+        let mut armed = progress.position() < self.section.1;
+        // Set after sending the player back to A, until it is seen before B again
+        let mut seeking = false;
+
+        loop {
+            while let Ok(command) = self.commands.try_recv() {
+                match command {
+                    LoopCommand::SetPoints(a, b) => {
+                        self.section = ordered(a, b);
+                        // Still on the old side of a seek, the next read decides
+                        armed = !seeking && progress.position() < self.section.1;
+                    },
+                    LoopCommand::Stop => return ABLoopEvent::Cancelled,
+                }
+            }
+            if self.sender.is_closed() {
+                return ABLoopEvent::Cancelled;
+            }
+
+            let position = progress.position();
+            if armed && position + POSITION_TOLERANCE >= self.section.1 {
+                if self.repeats.map_or(false, |x| self.count >= x) {
+                    return ABLoopEvent::Finished;
+                }
+                if !self.seek_to_a(&player, &progress, position) {
+                    return ABLoopEvent::Cancelled;
+                }
+                self.count += 1;
+                if !self.sender.send(ABLoopEvent::Looped { count: self.count }) {
+                    return ABLoopEvent::Cancelled;
+                }
+                // The player may take a moment to move, and reading it too soon still shows B
+                armed = false;
+                seeking = true;
+                continue;
+            }
+
+            let timeout = match progress.playback_status() {
+                PlaybackStatus::Playing if armed => match progress.time_until(self.section.1) {
+                    Some(wait) => wait.as_millis().clamp(1, MAX_WAIT_MS as u128) as u32,
+                    None => MAX_WAIT_MS,
+                },
+                _ => MAX_WAIT_MS,
+            };
+            let next = match task::block_on(future::timeout(Duration::from_millis(timeout as u64), updates.next())) {
+                // Seeks, playback status, rate and track changes all move the anchor
+                Ok(Some(x)) => x,
+                // The player quit
+                Ok(None) => return ABLoopEvent::Cancelled,
+                // Not every player announces the seek, so look for it
+                Err(_) if seeking => match ProgressClone::read(&player) {
+                    Ok(x) => x,
+                    Err(_) => return ABLoopEvent::Cancelled,
+                },
+                // The anchor still holds, the position is worked out from it
+                Err(_) => continue,
+            };
+            if next.track_key() != track {
+                return ABLoopEvent::TrackChanged;
+            }
+            let expected = progress.position();
+            let actual = next.position();
+            let seeked = expected.max(actual) - expected.min(actual) > SEEK_THRESHOLD;
+            if seeked || seeking {
+                // Only playing across B loops, landing past it by hand does not
+                armed = actual < self.section.1;
+                seeking = seeking && !armed;
+            }
+            progress = next;
+        }
+    }
+
+    fn seek_to_a(&self, player: &Player, progress: &ProgressClone, position: Duration) -> bool {
+        let a = self.section.0;
+        let result = match progress.metadata().track_id() {
+            Some(id) if id.as_str() != NO_TRACK => player.set_position(id, &a),
+            _ => player.seek(a.as_micros() as i64 - position.as_micros() as i64),
+        };
+        return result.is_ok();
+    }
+}
+
+impl Stream for ABLoop {
+    type Item = ABLoopEvent;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
+        self.reciever.poll_recv(cx)
+    }
+}
@@ -0,0 +1,591 @@
+//! Resume positions and named bookmarks for long tracks such as podcasts and audiobooks, kept in
+//! a JSON file. [`BookmarkStore::follow`] remembers where a player left each track and offers to
+//! seek back when it plays again.
+//!
+//! Needs the `bookmarks` feature.
+
+use std::{collections::{BTreeMap, HashMap}, fmt, fs, io, path::PathBuf, sync::{Arc, Mutex}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use async_std::{channel::Receiver, task, stream::Stream};
+use mpris::{Metadata, PlaybackStatus};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, fake_progress::ProgressClone, handle::PlayerHandle, quirks::{quirks_for, NO_TRACK}, signals::{Signal, SignalListener}, waker::{waking_channel, WakingReceiver, WakingSender}};
+
+/// How often the position of a playing track is saved while following a player.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longest time to wait without checking whether the offers were dropped.
+const MAX_WAIT_MS: u32 = 1000;
+
+/// Errors from reading or writing the bookmark file.
+#[derive(Debug)]
+pub enum BookmarkError {
+    /// The file could not be read or written.
+    Io(io::Error),
+    /// The file is not valid JSON, or the bookmarks could not be serialized.
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for BookmarkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BookmarkError::Io(e) => write!(f, "Could not access bookmark file: {}", e),
+            BookmarkError::Json(e) => write!(f, "Could not read or write bookmarks: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BookmarkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BookmarkError::Io(e) => Some(e),
+            BookmarkError::Json(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for BookmarkError {
+    fn from(e: io::Error) -> Self {
+        BookmarkError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for BookmarkError {
+    fn from(e: serde_json::Error) -> Self {
+        BookmarkError::Json(e)
+    }
+}
+
+/// Which tracks get a resume position and when it is thrown away. Named bookmarks are never
+/// expired.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookmarkPolicy {
+    /// Tracks shorter than this, or of unknown length, get no resume position.
+    pub min_length: Duration,
+    /// Positions before this are not worth resuming from.
+    pub min_position: Duration,
+    /// A track stopped within this much of its end counts as finished, and its resume position
+    /// is removed.
+    pub end_margin: Duration,
+    /// Resume positions older than this are removed.
+    pub max_age: Option<Duration>,
+    /// Only the newest this many resume positions are kept.
+    pub max_entries: Option<usize>,
+}
+
+impl Default for BookmarkPolicy {
+    fn default() -> Self {
+        BookmarkPolicy {
+            min_length: Duration::from_secs(10 * 60),
+            min_position: Duration::from_secs(30),
+            end_margin: Duration::from_secs(60),
+            max_age: Some(Duration::from_secs(90 * 24 * 60 * 60)),
+            max_entries: Some(500),
+        }
+    }
+}
+
+/// A position in a track.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bookmark {
+    position: Duration,
+    /// Seconds since the Unix epoch.
+    saved_at: u64,
+    title: Option<String>,
+    length: Option<Duration>,
+}
+
+impl Bookmark {
+    fn new(metadata: &Metadata, position: Duration) -> Self {
+        Bookmark {
+            position,
+            saved_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            title: metadata.title().map(|x| x.to_string()),
+            length: metadata.length(),
+        }
+    }
+
+    /// The position in the track.
+    pub fn position(&self) -> Duration {
+        self.position
+    }
+
+    /// When the bookmark was saved.
+    pub fn saved_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.saved_at)
+    }
+
+    /// The title of the track when the bookmark was saved.
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// The length of the track when the bookmark was saved.
+    pub fn length(&self) -> Option<Duration> {
+        self.length
+    }
+}
+
+/// What is stored in the bookmark file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Bookmarks {
+    resume: HashMap<String, Bookmark>,
+    named: HashMap<String, BTreeMap<String, Bookmark>>,
+}
+
+/// The key a track's bookmarks are stored under: its url, or its track id without one. Urls are
+/// preferred because they stay the same across players and playlists.
+pub fn bookmark_key(metadata: &Metadata) -> Option<String> {
+    if let Some(url) = metadata.url() {
+        return Some(url.to_string());
+    }
+    return match metadata.track_id() {
+        Some(id) if id.as_str() != NO_TRACK => Some(id.to_string()),
+        _ => None,
+    };
+}
+
+/// Resume positions and named bookmarks, stored in a JSON file that is rewritten on every change.
+#[derive(Debug)]
+pub struct BookmarkStore {
+    path: PathBuf,
+    policy: BookmarkPolicy,
+    bookmarks: Mutex<Bookmarks>,
+}
+
+impl BookmarkStore {
+    /// Opens the bookmark file at `path`, which is created on the first change. Expired resume
+    /// positions are removed right away, which only writes the file if there were any.
+    pub fn open(path: impl Into<PathBuf>, policy: BookmarkPolicy) -> Result<Self, BookmarkError> {
+        let path = path.into();
+        let bookmarks = match fs::read(&path) {
+            Ok(x) => serde_json::from_slice(&x)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Bookmarks::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let store = BookmarkStore { path, policy, bookmarks: Mutex::new(bookmarks) };
+        store.expire()?;
+        return Ok(store);
+    }
+
+    /// The rules used for resume positions.
+    pub fn policy(&self) -> &BookmarkPolicy {
+        &self.policy
+    }
+
+    /// Where the track was left, if it was left somewhere worth resuming from.
+    pub fn resume_position(&self, metadata: &Metadata) -> Option<Bookmark> {
+        let key = bookmark_key(metadata)?;
+        return self.bookmarks.lock().unwrap().resume.get(&key).cloned();
+    }
+
+    /// Saves `position` as where the track was left, following the [`BookmarkPolicy`]. A
+    /// position near the end removes the resume position instead. Returns whether a position was
+    /// saved.
+    pub fn remember(&self, metadata: &Metadata, position: Duration) -> Result<bool, BookmarkError> {
+        let key = match bookmark_key(metadata) {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+        let length = match metadata.length() {
+            Some(x) if x >= self.policy.min_length => x,
+            _ => return Ok(false),
+        };
+        if position + self.policy.end_margin >= length {
+            self.forget(metadata)?;
+            return Ok(false);
+        }
+        if position < self.policy.min_position {
+            return Ok(false);
+        }
+        let mut bookmarks = self.bookmarks.lock().unwrap();
+        bookmarks.resume.insert(key, Bookmark::new(metadata, position));
+        self.remove_expired(&mut bookmarks);
+        self.write(&bookmarks)?;
+        return Ok(true);
+    }
+
+    /// Removes the resume position of the track.
+    pub fn forget(&self, metadata: &Metadata) -> Result<(), BookmarkError> {
+        let key = match bookmark_key(metadata) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let mut bookmarks = self.bookmarks.lock().unwrap();
+        if bookmarks.resume.remove(&key).is_some() {
+            self.write(&bookmarks)?;
+        }
+        return Ok(());
+    }
+
+    /// Adds a bookmark called `name` to the track, replacing one with the same name. Returns
+    /// [`None`] if the track has neither a url nor a track id.
+    pub fn add_named(&self, metadata: &Metadata, name: &str, position: Duration) -> Result<Option<Bookmark>, BookmarkError> {
+        let key = match bookmark_key(metadata) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let bookmark = Bookmark::new(metadata, position);
+        let mut bookmarks = self.bookmarks.lock().unwrap();
+        bookmarks.named.entry(key).or_default().insert(name.to_string(), bookmark.clone());
+        self.write(&bookmarks)?;
+        return Ok(Some(bookmark));
+    }
+
+    /// The named bookmarks of the track, in order of position.
+    pub fn named(&self, metadata: &Metadata) -> Vec<(String, Bookmark)> {
+        let key = match bookmark_key(metadata) {
+            Some(x) => x,
+            None => return vec![],
+        };
+        let mut named = match self.bookmarks.lock().unwrap().named.get(&key) {
+            Some(x) => x.iter().map(|(name, bookmark)| (name.clone(), bookmark.clone())).collect::<Vec<_>>(),
+            None => vec![],
+        };
+        named.sort_by_key(|(_, bookmark)| bookmark.position);
+        return named;
+    }
+
+    /// Removes the bookmark called `name` from the track. Returns whether it existed.
+    pub fn remove_named(&self, metadata: &Metadata, name: &str) -> Result<bool, BookmarkError> {
+        let key = match bookmark_key(metadata) {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+        let mut bookmarks = self.bookmarks.lock().unwrap();
+        let removed = match bookmarks.named.get_mut(&key) {
+            Some(named) => named.remove(name).is_some(),
+            None => false,
+        };
+        if bookmarks.named.get(&key).map_or(false, |x| x.is_empty()) {
+            bookmarks.named.remove(&key);
+        }
+        if removed {
+            self.write(&bookmarks)?;
+        }
+        return Ok(removed);
+    }
+
+    /// Removes resume positions that are too old or too many, as set by the [`BookmarkPolicy`].
+    /// Returns how many were removed. The file is only written if any were.
+    pub fn expire(&self) -> Result<usize, BookmarkError> {
+        let mut bookmarks = self.bookmarks.lock().unwrap();
+        let removed = self.remove_expired(&mut bookmarks);
+        if removed > 0 {
+            self.write(&bookmarks)?;
+        }
+        return Ok(removed);
+    }
+
+    /// Removes expired resume positions without writing the file, returns how many there were.
+    fn remove_expired(&self, bookmarks: &mut Bookmarks) -> usize {
+        let before = bookmarks.resume.len();
+        if let Some(max_age) = self.policy.max_age {
+            let now = SystemTime::now();
+            bookmarks.resume.retain(|_, x| now.duration_since(x.saved_at()).map_or(true, |age| age <= max_age));
+        }
+        if let Some(max_entries) = self.policy.max_entries {
+            if bookmarks.resume.len() > max_entries {
+                let mut ages = bookmarks.resume.iter().map(|(key, x)| (x.saved_at, key.clone())).collect::<Vec<_>>();
+                ages.sort_unstable();
+                let excess = ages.len() - max_entries;
+                for (_, key) in ages.into_iter().take(excess) {
+                    bookmarks.resume.remove(&key);
+                }
+            }
+        }
+        return before - bookmarks.resume.len();
+    }
+
+    fn write(&self, bookmarks: &Bookmarks) -> Result<(), BookmarkError> {
+        // Write next to the file and rename, so a crash never leaves half a file
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(bookmarks)?)?;
+        fs::rename(&temporary, &self.path)?;
+        return Ok(());
+    }
+
+    /// Follows the player, saving the position of the current track every few seconds and when
+    /// it pauses or changes. Whenever a track with a resume position starts, a [`ResumeOffer`] is
+    /// sent; pass it to [`PlayerHandle::seek_to_bookmark`] to take it up. Makes a new thread that
+    /// runs until the player quits or the offers are dropped.
+    pub fn follow(self: &Arc<Self>, handle: &PlayerHandle) -> ResumeOffers {
+        let (sender, reciever) = waking_channel();
+        let store = self.clone();
+        let handle = handle.clone();
+        thread::spawn(move || {
+            store.follow_listener(&handle, &sender);
+            sender.close();
+        });
+        return ResumeOffers { reciever };
+    }
+
+    fn follow_listener(&self, handle: &PlayerHandle, sender: &WakingSender<ResumeOffer>) {
+        // Subscribe before reading, so no change is missed in between
+        let mut signals = match SignalListener::new(handle) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let player = match handle.find() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let quirks = quirks_for(&player);
+        let read = || {
+            let mut progress = ProgressClone::read(&player).ok()?;
+            quirks.normalize_progress(&mut progress);
+            Some(progress)
+        };
+        let mut current = match read() {
+            Some(x) => x,
+            None => return,
+        };
+        if !self.offer(&current, sender) {
+            return;
+        }
+        let mut last_saved = Instant::now();
+
+        loop {
+            if sender.is_closed() {
+                let _ = self.remember(current.metadata(), current.position());
+                return;
+            }
+            match signals.next_within(MAX_WAIT_MS) {
+                Some(Signal::PlayerQuit) => {
+                    let _ = self.remember(current.metadata(), current.position());
+                    return;
+                },
+                Some(Signal::PropertiesChanged { .. }) | Some(Signal::Other(_)) => {},
+                None if signals.is_closed() => return,
+                None => {
+                    if current.playback_status() == PlaybackStatus::Playing && last_saved.elapsed() >= SAVE_INTERVAL {
+                        let _ = self.remember(current.metadata(), current.position());
+                        last_saved = Instant::now();
+                    }
+                    continue;
+                },
+            }
+
+            let progress = match read() {
+                Some(x) => x,
+                None => return,
+            };
+            if bookmark_key(progress.metadata()) != bookmark_key(current.metadata()) {
+                // The old anchor still says where the last track got to
+                let _ = self.remember(current.metadata(), current.position());
+                current = progress;
+                last_saved = Instant::now();
+                if !self.offer(&current, sender) {
+                    return;
+                }
+                continue;
+            }
+            match progress.playback_status() {
+                PlaybackStatus::Paused => {
+                    let _ = self.remember(progress.metadata(), progress.position());
+                    last_saved = Instant::now();
+                },
+                // Stopping usually moves back to the start, where the track was is in the old anchor
+                PlaybackStatus::Stopped if current.playback_status() != PlaybackStatus::Stopped => {
+                    let _ = self.remember(current.metadata(), current.position());
+                    last_saved = Instant::now();
+                },
+                _ => {},
+            }
+            current = progress;
+        }
+    }
+
+    /// Sends an offer if the track has a resume position ahead of where it is. Returns false once
+    /// the offers were dropped.
+    fn offer(&self, progress: &ProgressClone, sender: &WakingSender<ResumeOffer>) -> bool {
+        if progress.playback_status() == PlaybackStatus::Stopped {
+            return true;
+        }
+        return match self.resume_position(progress.metadata()) {
+            Some(bookmark) if bookmark.position > progress.position() + self.policy.min_position => {
+                sender.send(ResumeOffer { metadata: progress.metadata().clone(), bookmark })
+            },
+            _ => true,
+        };
+    }
+}
+
+/// A track with a resume position started playing, see [`BookmarkStore::follow`].
+#[derive(Debug, Clone)]
+pub struct ResumeOffer {
+    metadata: Metadata,
+    bookmark: Bookmark,
+}
+
+impl ResumeOffer {
+    /// The track that started.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Where the track was left.
+    pub fn bookmark(&self) -> &Bookmark {
+        &self.bookmark
+    }
+}
+
+/// Stream of [`ResumeOffer`]s, see [`BookmarkStore::follow`].
+#[derive(Debug, Clone)]
+pub struct ResumeOffers {
+    reciever: WakingReceiver<ResumeOffer>,
+}
+
+impl ResumeOffers {
+    /// Access to the reciever used to send offers around.
+    pub fn get_reciever(&self) -> Receiver<ResumeOffer> {
+        self.reciever.reciever()
+    }
+}
+
+impl Stream for ResumeOffers {
+    type Item = ResumeOffer;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
+        self.reciever.poll_recv(cx)
+    }
+}
+
+impl PlayerHandle {
+    /// Moves the track `metadata` to the position of `bookmark`. Uses the track id when the track
+    /// has one, and seeks relative to the current position otherwise.
+    pub async fn seek_to_bookmark(&self, metadata: &Metadata, bookmark: &Bookmark) -> Result<(), Error> {
+        let position = bookmark.position();
+        return match metadata.track_id() {
+            Some(id) if id.as_str() != NO_TRACK => self.set_position(&id, position).await,
+            _ => {
+                let current = self.run(|player| player.get_position()).await?;
+                let offset = position.as_micros() as i64 - current.as_micros() as i64;
+                self.seek(offset).await
+            },
+        };
+    }
+
+    /// Takes up a [`ResumeOffer`], moving the track back to where it was left.
+    pub async fn resume(&self, offer: &ResumeOffer) -> Result<(), Error> {
+        self.seek_to_bookmark(offer.metadata(), offer.bookmark()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use mpris::MetadataValue;
+
+    use super::*;
+
+    /// A bookmark file of its own, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("mpris-async-bookmarks-{}-{}.json", name, process::id()));
+            let _ = fs::remove_file(&path);
+            TempFile(path)
+        }
+
+        fn open(&self, policy: BookmarkPolicy) -> BookmarkStore {
+            BookmarkStore::open(self.0.clone(), policy).unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn track(url: &str, length: u64) -> Metadata {
+        let mut fields = HashMap::new();
+        fields.insert("xesam:url".to_string(), MetadataValue::String(url.to_string()));
+        fields.insert("xesam:title".to_string(), MetadataValue::String("Episode".to_string()));
+        fields.insert("mpris:length".to_string(), MetadataValue::I64(length as i64 * 1_000_000));
+        return Metadata::from(fields);
+    }
+
+    fn secs(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    fn bookmark(saved_at: u64) -> Bookmark {
+        Bookmark { position: secs(600), saved_at, title: None, length: Some(secs(3600)) }
+    }
+
+    #[test]
+    fn remembers_where_tracks_were_left() {
+        let file = TempFile::new("resume");
+        let store = file.open(BookmarkPolicy::default());
+        let episode = track("file:///episode.mp3", 3600);
+        assert!(store.remember(&episode, secs(600)).unwrap());
+        assert_eq!(store.resume_position(&episode).unwrap().position(), secs(600));
+        assert_eq!(file.open(BookmarkPolicy::default()).resume_position(&episode).unwrap().position(), secs(600));
+
+        // Too early to be worth resuming, the old position stays
+        assert!(!store.remember(&episode, secs(10)).unwrap());
+        assert_eq!(store.resume_position(&episode).unwrap().position(), secs(600));
+        // Finished, so there is nothing to resume
+        assert!(!store.remember(&episode, secs(3590)).unwrap());
+        assert!(store.resume_position(&episode).is_none());
+        assert!(file.open(BookmarkPolicy::default()).resume_position(&episode).is_none());
+    }
+
+    #[test]
+    fn short_tracks_are_not_remembered() {
+        let file = TempFile::new("short");
+        let store = file.open(BookmarkPolicy::default());
+        let song = track("file:///song.mp3", 200);
+        assert!(!store.remember(&song, secs(100)).unwrap());
+        assert!(store.resume_position(&song).is_none());
+        assert!(!file.0.exists());
+    }
+
+    #[test]
+    fn keeps_named_bookmarks_in_order() {
+        let file = TempFile::new("named");
+        let store = file.open(BookmarkPolicy::default());
+        let book = track("file:///book.m4b", 36000);
+        store.add_named(&book, "chapter 3", secs(900)).unwrap();
+        store.add_named(&book, "chapter 1", secs(300)).unwrap();
+        store.add_named(&book, "chapter 3", secs(1200)).unwrap();
+        let named = |store: &BookmarkStore| store.named(&book).into_iter().map(|(name, x)| (name, x.position())).collect::<Vec<_>>();
+        assert_eq!(named(&store), vec![("chapter 1".to_string(), secs(300)), ("chapter 3".to_string(), secs(1200))]);
+
+        assert!(store.remove_named(&book, "chapter 1").unwrap());
+        assert!(!store.remove_named(&book, "chapter 1").unwrap());
+        assert_eq!(named(&file.open(BookmarkPolicy::default())), vec![("chapter 3".to_string(), secs(1200))]);
+    }
+
+    #[test]
+    fn opening_does_not_write_without_changes() {
+        let file = TempFile::new("untouched");
+        file.open(BookmarkPolicy::default());
+        assert!(!file.0.exists());
+    }
+
+    #[test]
+    fn expires_old_and_excess_positions() {
+        let file = TempFile::new("expire");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut bookmarks = Bookmarks::default();
+        bookmarks.resume.insert("old".to_string(), bookmark(now - 100 * 24 * 60 * 60));
+        bookmarks.resume.insert("older".to_string(), bookmark(now - 30));
+        bookmarks.resume.insert("middle".to_string(), bookmark(now - 20));
+        bookmarks.resume.insert("newest".to_string(), bookmark(now - 10));
+        fs::write(&file.0, serde_json::to_vec(&bookmarks).unwrap()).unwrap();
+
+        let policy = BookmarkPolicy { max_entries: Some(2), ..BookmarkPolicy::default() };
+        let store = file.open(policy.clone());
+        let mut left = store.bookmarks.lock().unwrap().resume.keys().cloned().collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, vec!["middle", "newest"]);
+        assert_eq!(file.open(policy).bookmarks.lock().unwrap().resume.len(), 2);
+        assert_eq!(store.expire().unwrap(), 0);
+    }
+}
@@ -0,0 +1,309 @@
+//! [`Capabilities`] describes what a player can do right now. [`CapabilitiesStream`] reports
+//! changes, and the guarded control methods on [`PlayerHandle`] fail with
+//! [`Error::Unsupported`] instead of sending calls the player would ignore.
+
+use std::{fmt, thread, time::Duration};
+
+use async_std::{channel::Receiver, task, stream::Stream};
+use dbus::{arg::{prop_cast, PropMap}, ffidisp::stdintf::org_freedesktop_dbus::Properties};
+use mpris::{Player, TrackID};
+
+use crate::{error::Error, handle::{PlayerHandle, DBUS_TIMEOUT_MS, MPRIS_PATH, PLAYER_INTERFACE}, quirks::quirks_for, signals::{Signal, SignalListener}, waker::{waking_channel, WakingReceiver, WakingSender}};
+
+/// A single thing a player might be able to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// `CanControl`, the player can be controlled at all.
+    Control,
+    /// `CanPlay`
+    Play,
+    /// `CanPause`
+    Pause,
+    /// `CanSeek`
+    Seek,
+    /// `CanGoNext`
+    GoNext,
+    /// `CanGoPrevious`
+    GoPrevious,
+}
+
+impl Capability {
+    /// The name of the MPRIS property for this capability.
+    pub fn property(&self) -> &'static str {
+        match self {
+            Capability::Control => "CanControl",
+            Capability::Play => "CanPlay",
+            Capability::Pause => "CanPause",
+            Capability::Seek => "CanSeek",
+            Capability::GoNext => "CanGoNext",
+            Capability::GoPrevious => "CanGoPrevious",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.property())
+    }
+}
+
+/// What a player can do at the time it was read. A player that cannot be controlled cannot do
+/// anything else either, so every getter returns false when [`can_control`](Self::can_control) does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    can_control: bool,
+    can_play: bool,
+    can_pause: bool,
+    can_seek: bool,
+    can_go_next: bool,
+    can_go_previous: bool,
+}
+
+impl Capabilities {
+    /// Reads the capabilities out of the properties of the player interface.
+    pub(crate) fn from_properties(properties: &PropMap) -> Self {
+        let mut capabilities = Capabilities::default();
+        capabilities.update(properties);
+        return capabilities;
+    }
+
+    /// Applies a `PropertiesChanged` signal. Returns true if anything changed.
+    pub(crate) fn update(&mut self, changed: &PropMap) -> bool {
+        let old = *self;
+        for capability in [Capability::Control, Capability::Play, Capability::Pause, Capability::Seek, Capability::GoNext, Capability::GoPrevious] {
+            if let Some(x) = prop_cast::<bool>(changed, capability.property()) {
+                *self.field(capability) = *x;
+            }
+        }
+        return old != *self;
+    }
+
+    fn field(&mut self, capability: Capability) -> &mut bool {
+        match capability {
+            Capability::Control => &mut self.can_control,
+            Capability::Play => &mut self.can_play,
+            Capability::Pause => &mut self.can_pause,
+            Capability::Seek => &mut self.can_seek,
+            Capability::GoNext => &mut self.can_go_next,
+            Capability::GoPrevious => &mut self.can_go_previous,
+        }
+    }
+
+    /// Whether the player supports `capability`.
+    pub fn supports(&self, capability: Capability) -> bool {
+        let value = match capability {
+            Capability::Control => self.can_control,
+            Capability::Play => self.can_play,
+            Capability::Pause => self.can_pause,
+            Capability::Seek => self.can_seek,
+            Capability::GoNext => self.can_go_next,
+            Capability::GoPrevious => self.can_go_previous,
+        };
+        return self.can_control && value;
+    }
+
+    /// Whether the player can be controlled at all.
+    pub fn can_control(&self) -> bool {
+        self.can_control
+    }
+
+    /// Whether the player can start playing.
+    pub fn can_play(&self) -> bool {
+        self.supports(Capability::Play)
+    }
+
+    /// Whether the player can be paused.
+    pub fn can_pause(&self) -> bool {
+        self.supports(Capability::Pause)
+    }
+
+    /// Whether the player can seek or change position.
+    pub fn can_seek(&self) -> bool {
+        self.supports(Capability::Seek)
+    }
+
+    /// Whether the player can skip to the next track.
+    pub fn can_go_next(&self) -> bool {
+        self.supports(Capability::GoNext)
+    }
+
+    /// Whether the player can go back to the previous track.
+    pub fn can_go_previous(&self) -> bool {
+        self.supports(Capability::GoPrevious)
+    }
+}
+
+impl PlayerHandle {
+    /// Reads the current [`Capabilities`] of the player.
+    pub async fn capabilities(&self) -> Result<Capabilities, Error> {
+        self.run_raw(|path| path.get_all(PLAYER_INTERFACE).map(|x| Capabilities::from_properties(&x))).await
+    }
+
+    /// Creates a new [`CapabilitiesStream`] for the player.
+    pub fn capabilities_stream(&self) -> CapabilitiesStream {
+        CapabilitiesStream::new(self)
+    }
+
+    /// Starts playback, or fails with [`Error::Unsupported`] if the player can't.
+    pub async fn play(&self) -> Result<(), Error> {
+        self.run(|player| {
+            require(player, Capability::Play)?;
+            player.play().map_err(Error::from)
+        }).await
+    }
+
+    /// Pauses playback, or fails with [`Error::Unsupported`] if the player can't.
+    pub async fn pause(&self) -> Result<(), Error> {
+        self.run(|player| {
+            require(player, Capability::Pause)?;
+            player.pause().map_err(Error::from)
+        }).await
+    }
+
+    /// Toggles between playing and paused, or fails with [`Error::Unsupported`] if the player
+    /// can't pause.
+    pub async fn play_pause(&self) -> Result<(), Error> {
+        self.run(|player| {
+            require(player, Capability::Pause)?;
+            player.play_pause().map_err(Error::from)
+        }).await
+    }
+
+    /// Stops playback, or fails with [`Error::Unsupported`] if the player can't be controlled.
+    pub async fn stop(&self) -> Result<(), Error> {
+        self.run(|player| {
+            require(player, Capability::Control)?;
+            player.stop().map_err(Error::from)
+        }).await
+    }
+
+    /// Skips to the next track, or fails with [`Error::Unsupported`] if the player can't.
+    pub async fn next(&self) -> Result<(), Error> {
+        self.run(|player| {
+            require(player, Capability::GoNext)?;
+            player.next().map_err(Error::from)
+        }).await
+    }
+
+    /// Goes back to the previous track, or fails with [`Error::Unsupported`] if the player can't.
+    pub async fn previous(&self) -> Result<(), Error> {
+        self.run(|player| {
+            require(player, Capability::GoPrevious)?;
+            player.previous().map_err(Error::from)
+        }).await
+    }
+
+    /// Seeks by `offset_in_microseconds`, which may be negative. Fails with
+    /// [`Error::Unsupported`] if the player can't seek.
+    pub async fn seek(&self, offset_in_microseconds: i64) -> Result<(), Error> {
+        self.run(move |player| {
+            require(player, Capability::Seek)?;
+            player.seek(offset_in_microseconds).map_err(Error::from)
+        }).await
+    }
+
+    /// Moves to `position` in the track `track_id`. Fails with [`Error::Unsupported`] if the
+    /// player can't seek.
+    pub async fn set_position(&self, track_id: &TrackID, position: Duration) -> Result<(), Error> {
+        let track_id = track_id.clone();
+        self.run(move |player| {
+            require(player, Capability::Seek)?;
+            player.set_position(track_id, &position).map_err(Error::from)
+        }).await
+    }
+
+    /// The volume of the player in the 0.0-1.0 range, even for players that use another one,
+    /// see [`Quirks::volume_scale`](crate::quirks::Quirks::volume_scale).
+    pub async fn get_volume(&self) -> Result<f64, Error> {
+        self.run(|player| {
+            let volume = player.get_volume()?;
+            Ok::<f64, Error>(quirks_for(player).normalize_volume(volume))
+        }).await
+    }
+
+    /// Sets the volume of the player, in the 0.0-1.0 range even for players that use another
+    /// one. Fails with [`Error::Unsupported`] if the player can't be controlled.
+    pub async fn set_volume(&self, volume: f64) -> Result<(), Error> {
+        self.run(move |player| {
+            require(player, Capability::Control)?;
+            player.set_volume(quirks_for(player).denormalize_volume(volume)).map_err(Error::from)
+        }).await
+    }
+}
+
+/// Checks `capability` before a control call is sent.
+fn require(player: &Player, capability: Capability) -> Result<(), Error> {
+    let supported = player.can_control()? && match capability {
+        Capability::Control => true,
+        Capability::Play => player.can_play()?,
+        Capability::Pause => player.can_pause()?,
+        Capability::Seek => player.can_seek()?,
+        Capability::GoNext => player.can_go_next()?,
+        Capability::GoPrevious => player.can_go_previous()?,
+    };
+    if !supported {
+        return Err(Error::Unsupported(capability));
+    }
+    return Ok(());
+}
+
+/// Stream of the [`Capabilities`] of a player. The first item is the capabilities at the time
+/// the stream was created, after that an item is only sent when they change. Makes a new thread
+/// to listen for changes, which closes once the player has quit.
+#[derive(Debug, Clone)]
+pub struct CapabilitiesStream {
+    reciever: WakingReceiver<Capabilities>,
+}
+
+impl CapabilitiesStream {
+    /// Creates a new [`CapabilitiesStream`]. Streams made from cloning share the same thread.
+    pub fn new(handle: &PlayerHandle) -> Self {
+        let (sender, reciever) = waking_channel();
+        let handle = handle.clone();
+        thread::spawn(move || {
+            CapabilitiesStream::capabilities_listener(&handle, &sender);
+            sender.close();
+        });
+        return CapabilitiesStream { reciever };
+    }
+
+    fn capabilities_listener(handle: &PlayerHandle, sender: &WakingSender<Capabilities>) {
+        // Subscribe before reading, so no change is missed in between
+        let signals = match SignalListener::new(handle) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let properties = match handle.bus().connect() {
+            Ok(connection) => connection.with_path(handle.bus_name(), MPRIS_PATH, DBUS_TIMEOUT_MS).get_all(PLAYER_INTERFACE),
+            Err(_) => return,
+        };
+        let mut capabilities = match properties {
+            Ok(x) => Capabilities::from_properties(&x),
+            Err(_) => return,
+        };
+        if !sender.send(capabilities) {
+            return;
+        }
+        for signal in signals {
+            match signal {
+                // Sends the capabilities when they changed, and stops once nobody listens
+                Signal::PropertiesChanged { interface, changed, .. } if interface == PLAYER_INTERFACE && capabilities.update(&changed) && !sender.send(capabilities) => return,
+                Signal::PlayerQuit => return,
+                _ => {},
+            }
+        }
+    }
+
+    /// Access to the reciever used to send capabilities around.
+    pub fn get_reciever(&self) -> Receiver<Capabilities> {
+        self.reciever.reciever()
+    }
+}
+
+impl Stream for CapabilitiesStream {
+    type Item = Capabilities;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
+        self.reciever.poll_recv(cx)
+    }
+}
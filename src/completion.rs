@@ -0,0 +1,401 @@
+//! [`CompletionStream`] works out how each track ended: played to the end, skipped, or
+//! restarted, and reports milestones along the way.
+
+use std::{thread, time::{Duration, Instant}};
+
+use async_std::{channel::Receiver, future, task, stream::{Stream, StreamExt}};
+use mpris::{Event, Metadata, PlaybackStatus};
+
+use crate::{events::{PlayerEvent, PlayerEventsStream}, fake_progress::ProgressClone, handle::PlayerHandle, listen::END_TOLERANCE, waker::{waking_channel, WakingReceiver, WakingSender}};
+
+/// The milestones used by [`CompletionStream::new`], in percent.
+pub const DEFAULT_MILESTONES: [u8; 3] = [25, 50, 75];
+
+/// A jump in position larger than this, compared to where the track should be, is a seek.
+const SEEK_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// Seeking back to within this much of the start counts as restarting the track...
+const RESTART_WINDOW: Duration = Duration::from_secs(3);
+
+/// ...as long as the track had played for at least this long.
+const RESTART_MIN_POSITION: Duration = Duration::from_secs(10);
+
+/// Seeking to within this much of the end and letting it finish counts as skipping.
+const SEEK_END_WINDOW: Duration = Duration::from_secs(10);
+
+/// Longest time to wait without checking whether the stream was dropped.
+const MAX_WAIT_MS: u32 = 1000;
+
+/// Items of a [`CompletionStream`].
+#[derive(Debug, Clone)]
+pub enum CompletionEvent {
+    /// The track played until its end.
+    Completed(Metadata),
+    /// Another track started, or playback stopped, before the end. `at` is where the track
+    /// was, and `fraction` how far along that was from 0.0 to 1.0. Seeking to the end counts as
+    /// skipping from where the seek started.
+    Skipped { metadata: Metadata, at: Duration, fraction: f64 },
+    /// The track was seeked back to its start, or started again after it ended or was stopped.
+    Restarted(Metadata),
+    /// The track passed a milestone, in percent of its length, by playing through it. Milestones
+    /// skipped by seeking past them are not reported.
+    Milestone { metadata: Metadata, percent: u8 },
+}
+
+/// Stream of [`CompletionEvent`]s for a player. Makes a new thread that follows the position of
+/// the player from a [`PlayerEventsStream`], and sleeps until the next milestone or the end of the track in
+/// between. The stream ends when the player quits.
+#[derive(Debug, Clone)]
+pub struct CompletionStream {
+    reciever: WakingReceiver<CompletionEvent>,
+}
+
+/// What the listener knows about the current track.
+struct TrackState {
+    key: Option<String>,
+    anchor: ProgressClone,
+    milestones_reached: Vec<u8>,
+    /// Whether how the track ended was already reported.
+    ended: bool,
+    /// Where the last forward seek started, if it went close to the end.
+    seeked_to_end_from: Option<Duration>,
+}
+
+impl TrackState {
+    fn new(anchor: ProgressClone) -> Self {
+        TrackState { key: anchor.track_key(), anchor, milestones_reached: vec![], ended: false, seeked_to_end_from: None }
+    }
+
+    fn fraction(&self, position: Duration) -> Option<f64> {
+        let length = self.anchor.length()?;
+        if length.is_zero() {
+            return None;
+        }
+        return Some((position.as_secs_f64() / length.as_secs_f64()).min(1.0));
+    }
+
+    fn near_end(&self, position: Duration) -> bool {
+        match self.anchor.length() {
+            Some(length) => position + END_TOLERANCE >= length,
+            None => false,
+        }
+    }
+
+    /// The position of the track at `at`, as expected from the anchor.
+    fn position_at(&self, at: Instant) -> Duration {
+        let mut position = self.anchor.initial_position();
+        if self.anchor.playback_status() == PlaybackStatus::Playing {
+            position += at.saturating_duration_since(*self.anchor.created_at()).mul_f64(self.anchor.playback_rate().max(0.0));
+        }
+        return match self.anchor.length() {
+            Some(length) => position.min(length),
+            None => position,
+        };
+    }
+
+    /// Reports the milestones passed by playing up to `position`, and the end of the track if
+    /// the player stopped or paused there.
+    fn check(&mut self, position: Duration, milestones: &[u8]) -> Vec<CompletionEvent> {
+        let mut events = vec![];
+        if let Some(fraction) = self.fraction(position) {
+            for percent in milestones {
+                if fraction * 100.0 >= *percent as f64 && !self.milestones_reached.contains(percent) {
+                    self.milestones_reached.push(*percent);
+                    events.push(CompletionEvent::Milestone { metadata: self.anchor.metadata().clone(), percent: *percent });
+                }
+            }
+        }
+        // Players that stay on the last track stop or pause at its end instead of moving on
+        if self.anchor.playback_status() != PlaybackStatus::Playing && self.near_end(position) {
+            if let Some(event) = self.ending(position) {
+                self.ended = true;
+                events.push(event);
+            }
+        }
+        return events;
+    }
+
+    /// How the track ended, at `position`. [`None`] if that was already reported.
+    fn ending(&self, position: Duration) -> Option<CompletionEvent> {
+        if self.ended {
+            return None;
+        }
+        let metadata = self.anchor.metadata().clone();
+        return match (self.near_end(position), self.seeked_to_end_from) {
+            (true, Some(from)) => Some(CompletionEvent::Skipped { metadata, at: from, fraction: self.fraction(from).unwrap_or(0.0) }),
+            (true, None) => Some(CompletionEvent::Completed(metadata)),
+            (false, _) => Some(CompletionEvent::Skipped { metadata, at: position, fraction: self.fraction(position).unwrap_or(0.0) }),
+        };
+    }
+}
+
+impl CompletionStream {
+    /// Creates a new [`CompletionStream`] with the [`DEFAULT_MILESTONES`].
+    pub fn new(handle: &PlayerHandle) -> Self {
+        return CompletionStream::with_milestones(handle, &DEFAULT_MILESTONES);
+    }
+
+    /// Creates a new [`CompletionStream`] reporting the given milestones, in percent.
+    pub fn with_milestones(handle: &PlayerHandle, milestones: &[u8]) -> Self {
+        let (sender, reciever) = waking_channel();
+        let handle = handle.clone();
+        let mut milestones = milestones.iter().copied().filter(|x| *x > 0 && *x < 100).collect::<Vec<u8>>();
+        milestones.sort_unstable();
+        milestones.dedup();
+        thread::spawn(move || {
+            CompletionStream::completion_listener(&handle, &milestones, &sender);
+            sender.close();
+        });
+        return CompletionStream { reciever };
+    }
+
+    fn completion_listener(handle: &PlayerHandle, milestones: &[u8], sender: &WakingSender<CompletionEvent>) {
+        let mut player = match handle.find() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let mut player_events = PlayerEventsStream::with_bus(&player, handle.bus().clone());
+        let mut track = match ProgressClone::read(&player) {
+            Ok(x) => TrackState::new(x),
+            Err(_) => return,
+        };
+
+        loop {
+            let position = track.position_at(Instant::now());
+            for event in track.check(position, milestones) {
+                if !sender.send(event) {
+                    return;
+                }
+            }
+
+            let timeout = CompletionStream::wait_time(&track, milestones, position);
+            if sender.is_closed() {
+                return;
+            }
+            match task::block_on(future::timeout(Duration::from_millis(timeout as u64), player_events.next())) {
+                Ok(Some(PlayerEvent::Player(Event::PlayerShutDown))) | Ok(None) => return,
+                // The old player is tied to the dead connection
+                Ok(Some(PlayerEvent::Reconnected)) => {
+                    player = match handle.find() {
+                        Ok(x) => x,
+                        Err(_) => return,
+                    };
+                },
+                // Seeked, playback status, rate and track changes all move the anchor
+                Ok(Some(PlayerEvent::Player(_))) => {},
+                // Nothing changed, the anchor still holds
+                Ok(Some(PlayerEvent::Root(_))) | Err(_) => continue,
+            }
+
+            let anchor = match ProgressClone::read(&player) {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+            for event in CompletionStream::apply(&mut track, anchor, milestones) {
+                if !sender.send(event) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Moves the state to a new anchor, reporting track changes and seeks.
+    fn apply(track: &mut TrackState, anchor: ProgressClone, milestones: &[u8]) -> Vec<CompletionEvent> {
+        let mut events = vec![];
+        let expected = track.position_at(*anchor.created_at());
+        let position = anchor.position();
+
+        if anchor.track_key() != track.key {
+            if let Some(event) = track.ending(expected) {
+                events.push(event);
+            }
+            *track = TrackState::new(anchor);
+            return events;
+        }
+
+        let stopped = anchor.playback_status() == PlaybackStatus::Stopped;
+        let was_stopped = track.anchor.playback_status() == PlaybackStatus::Stopped;
+        if stopped {
+            if !was_stopped {
+                if let Some(event) = track.ending(expected) {
+                    events.push(event);
+                }
+                track.ended = true;
+            }
+            track.anchor = anchor;
+            return events;
+        }
+
+        let backwards = expected.checked_sub(position).map_or(false, |x| x > SEEK_THRESHOLD);
+        let forwards = position.checked_sub(expected).map_or(false, |x| x > SEEK_THRESHOLD);
+        let from_start = was_stopped || (backwards && position <= RESTART_WINDOW);
+        if from_start && (expected >= RESTART_MIN_POSITION || track.ended) {
+            events.push(CompletionEvent::Restarted(anchor.metadata().clone()));
+            let mut restarted = TrackState::new(anchor);
+            restarted.key = track.key.clone();
+            *track = restarted;
+            return events;
+        }
+        if forwards {
+            track.seeked_to_end_from = match track.anchor.length() {
+                Some(length) if position + SEEK_END_WINDOW >= length => Some(expected),
+                _ => None,
+            };
+            // Milestones jumped over were not listened to, they are reached without a report
+            if let (Some(from), Some(to)) = (track.fraction(expected), track.fraction(position)) {
+                for percent in milestones {
+                    let at = *percent as f64 / 100.0;
+                    if at > from && at <= to && !track.milestones_reached.contains(percent) {
+                        track.milestones_reached.push(*percent);
+                    }
+                }
+            }
+        }
+        track.anchor = anchor;
+        return events;
+    }
+
+    /// How long until something could happen without the player sending a signal: the next
+    /// milestone or the end of the track.
+    fn wait_time(track: &TrackState, milestones: &[u8], position: Duration) -> u32 {
+        if track.anchor.playback_status() != PlaybackStatus::Playing {
+            return MAX_WAIT_MS;
+        }
+        let length = match track.anchor.length() {
+            Some(x) => x,
+            None => return MAX_WAIT_MS,
+        };
+        let next = milestones.iter()
+            .filter(|x| !track.milestones_reached.contains(x))
+            .map(|x| length.mul_f64(*x as f64 / 100.0))
+            .chain(std::iter::once(length.saturating_sub(END_TOLERANCE)))
+            .filter(|x| *x > position)
+            .min();
+        return match next.and_then(|x| track.anchor.time_until(x)) {
+            Some(wait) => wait.as_millis().clamp(1, MAX_WAIT_MS as u128) as u32,
+            None => MAX_WAIT_MS,
+        };
+    }
+
+    /// Access to the reciever used to send events around.
+    pub fn get_reciever(&self) -> Receiver<CompletionEvent> {
+        self.reciever.reciever()
+    }
+}
+
+impl PlayerHandle {
+    /// Creates a new [`CompletionStream`] for the player.
+    pub fn completion_events(&self) -> CompletionStream {
+        CompletionStream::new(self)
+    }
+}
+
+impl Stream for CompletionStream {
+    type Item = CompletionEvent;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
+        self.reciever.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mpris::MetadataValue;
+
+    use super::*;
+
+    fn progress(track: u32, length: u64, status: PlaybackStatus, position: u64, instant: Instant) -> ProgressClone {
+        let mut fields = HashMap::new();
+        fields.insert("mpris:trackid".to_string(), MetadataValue::String(format!("/org/mpris/MediaPlayer2/Track/{}", track)));
+        fields.insert("mpris:length".to_string(), MetadataValue::I64(length as i64 * 1_000_000));
+        return ProgressClone::from_parts(Metadata::from(fields), status, Duration::from_secs(position), instant);
+    }
+
+    /// A 300 second track that started playing at `start`.
+    fn playing(start: Instant) -> TrackState {
+        TrackState::new(progress(1, 300, PlaybackStatus::Playing, 0, start))
+    }
+
+    fn secs(start: Instant, seconds: u64) -> Instant {
+        start + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn playing_to_the_end_completes() {
+        let start = Instant::now();
+        let mut track = playing(start);
+        let events = CompletionStream::apply(&mut track, progress(2, 300, PlaybackStatus::Playing, 0, secs(start, 298)), &DEFAULT_MILESTONES);
+        assert!(matches!(events.as_slice(), [CompletionEvent::Completed(_)]));
+    }
+
+    #[test]
+    fn changing_track_early_skips() {
+        let start = Instant::now();
+        let mut track = playing(start);
+        let events = CompletionStream::apply(&mut track, progress(2, 300, PlaybackStatus::Playing, 0, secs(start, 60)), &DEFAULT_MILESTONES);
+        match events.as_slice() {
+            [CompletionEvent::Skipped { at, fraction, .. }] => {
+                assert_eq!(*at, Duration::from_secs(60));
+                assert!((fraction - 0.2).abs() < 1e-9);
+            },
+            other => panic!("expected a skip, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn seeking_to_the_end_skips_from_where_it_was() {
+        let start = Instant::now();
+        let mut track = playing(start);
+        assert!(CompletionStream::apply(&mut track, progress(1, 300, PlaybackStatus::Playing, 295, secs(start, 30)), &DEFAULT_MILESTONES).is_empty());
+        let events = CompletionStream::apply(&mut track, progress(2, 300, PlaybackStatus::Playing, 0, secs(start, 35)), &DEFAULT_MILESTONES);
+        assert!(matches!(events.as_slice(), [CompletionEvent::Skipped { at, .. }] if *at == Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn seeking_back_to_the_start_restarts() {
+        let start = Instant::now();
+        let mut track = playing(start);
+        let events = CompletionStream::apply(&mut track, progress(1, 300, PlaybackStatus::Playing, 1, secs(start, 30)), &DEFAULT_MILESTONES);
+        assert!(matches!(events.as_slice(), [CompletionEvent::Restarted(_)]));
+        assert_eq!(track.key.as_deref(), Some("/org/mpris/MediaPlayer2/Track/1"));
+
+        // Right after starting it is just a seek
+        let mut track = playing(start);
+        assert!(CompletionStream::apply(&mut track, progress(1, 300, PlaybackStatus::Playing, 0, secs(start, 5)), &DEFAULT_MILESTONES).is_empty());
+    }
+
+    #[test]
+    fn milestones_are_reported_when_played_through() {
+        let start = Instant::now();
+        let mut track = playing(start);
+        assert!(track.check(Duration::from_secs(74), &DEFAULT_MILESTONES).is_empty());
+        let events = track.check(Duration::from_secs(160), &DEFAULT_MILESTONES);
+        let percents = events.iter().map(|x| match x {
+            CompletionEvent::Milestone { percent, .. } => *percent,
+            other => panic!("expected a milestone, got {:?}", other),
+        }).collect::<Vec<_>>();
+        assert_eq!(percents, vec![25, 50]);
+        assert!(track.check(Duration::from_secs(170), &DEFAULT_MILESTONES).is_empty());
+    }
+
+    #[test]
+    fn milestones_seeked_past_are_not_reported() {
+        let start = Instant::now();
+        let mut track = playing(start);
+        assert!(CompletionStream::apply(&mut track, progress(1, 300, PlaybackStatus::Playing, 200, secs(start, 30)), &DEFAULT_MILESTONES).is_empty());
+        assert_eq!(track.milestones_reached, vec![25, 50]);
+        let events = track.check(Duration::from_secs(230), &DEFAULT_MILESTONES);
+        assert!(matches!(events.as_slice(), [CompletionEvent::Milestone { percent: 75, .. }]));
+    }
+
+    #[test]
+    fn short_tracks_paused_at_the_end_complete() {
+        let start = Instant::now();
+        let mut track = TrackState::new(progress(1, 8, PlaybackStatus::Paused, 8, start));
+        let events = track.check(Duration::from_secs(8), &[]);
+        assert!(matches!(events.as_slice(), [CompletionEvent::Completed(_)]));
+        assert!(track.check(Duration::from_secs(8), &[]).is_empty());
+    }
+}
@@ -0,0 +1,39 @@
+//! [`BusConfig`] picks the DBus bus that players are looked for on. Everything uses the current
+//! user's session bus unless told otherwise.
+
+use dbus::ffidisp::{BusType, Connection};
+use mpris::{DBusError, PlayerFinder};
+
+/// Which DBus bus to connect to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum BusConfig {
+    /// The session bus of the current user. This is what [`PlayerFinder::new`] uses.
+    #[default]
+    Session,
+    /// The system wide bus.
+    System,
+    /// An explicit bus address, such as `unix:path=/run/user/1000/bus`.
+    Address(String),
+}
+
+impl BusConfig {
+    /// Creates a new [`PlayerFinder`] with its own connection to this bus.
+    pub fn finder(&self) -> Result<PlayerFinder, DBusError> {
+        return Ok(PlayerFinder::for_connection(self.connect()?));
+    }
+
+    /// Opens a new private connection to this bus.
+    pub(crate) fn connect(&self) -> Result<Connection, DBusError> {
+        let connection = match self {
+            BusConfig::Session => Connection::get_private(BusType::Session)?,
+            BusConfig::System => Connection::get_private(BusType::System)?,
+            BusConfig::Address(address) => {
+                let connection = Connection::open_private(address)?;
+                // Buses opened by address need to be told who we are before they can be used
+                connection.register()?;
+                connection
+            },
+        };
+        return Ok(connection);
+    }
+}
@@ -0,0 +1,57 @@
+//! [`Error`] is the error type for the fallible streams in this crate.
+
+use std::fmt;
+
+use mpris::{DBusError, FindingError};
+
+use crate::capabilities::Capability;
+
+/// Errors yielded by streams such as [`crate::player::TryPlayerStream`]. These are usually
+/// transient, so the stream keeps running after yielding one.
+#[derive(Debug)]
+pub enum Error {
+    /// Could not connect to DBus. The bus might have gone away or not be running yet.
+    Connection(DBusError),
+    /// Something went wrong while talking to DBus.
+    DBus(DBusError),
+    /// A player quit between being found and being used.
+    PlayerQuit(String),
+    /// The player does not currently support the command that was attempted.
+    Unsupported(Capability),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connection(e) => write!(f, "Could not connect to DBus: {}", e),
+            Error::DBus(e) => write!(f, "DBus error: {}", e),
+            Error::PlayerQuit(identity) => write!(f, "Player {} quit while it was being used", identity),
+            Error::Unsupported(capability) => write!(f, "Player does not support this right now ({} is false)", capability),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connection(e) | Error::DBus(e) => Some(e),
+            Error::PlayerQuit(_) | Error::Unsupported(_) => None,
+        }
+    }
+}
+
+impl Error {
+    /// Converts the error from looking up a player by its identity.
+    pub(crate) fn from_finding(e: FindingError, identity: &str) -> Self {
+        match e {
+            FindingError::NoPlayerFound => Error::PlayerQuit(identity.to_string()),
+            FindingError::DBusError(e) => Error::DBus(e),
+        }
+    }
+}
+
+impl From<DBusError> for Error {
+    fn from(e: DBusError) -> Self {
+        Error::DBus(e)
+    }
+}
@@ -1,99 +1,307 @@
 //! [`PlayerEventsStream`] handles when new player events are emitted by a given player.
 //! Alternatively, the class gives a reciever which can be used to track events.
 
-use std::{task::{Waker, Poll}, thread};
+use std::{collections::HashSet, sync::{Arc, Mutex}, thread};
 
-use async_std::{channel::{Sender, Receiver, unbounded, TryRecvError}, task, stream::Stream};
-use mpris::{Player, Event, PlayerFinder};
+use async_std::{channel::Receiver, task, stream::{Stream, StreamExt}};
+use mpris::{Player, Event, FindingError, Metadata, PlaybackStatus};
+
+use crate::{connection::BusConfig, handle::PlayerHandle, player::TryPlayerStream, quirks::quirks_for, reconnect::{Backoff, PLAYER_LOOKUP_ATTEMPTS}, root::{RootEvent, ROOT_INTERFACE}, signals::{Signal, SignalListener}, waker::{waking_channel, WakingReceiver, WakingSender}};
+
+/// Items of a [`PlayerEventsStream`].
+#[derive(Debug)]
+pub enum PlayerEvent {
+    /// An [`Event`] emitted by the player.
+    Player(Event),
+    /// The connection to DBus was lost and has been re-established. Anything that happened while
+    /// disconnected is lost, so the current state of the player is sent right after this as
+    /// [`PlayerEvent::Player`] events.
+    Reconnected,
+    /// A property of the root interface changed, see [`RootEvent`].
+    Root(RootEvent),
+}
 
 /// Infinite Stream which tracks the Events emitted by a player. Streams created with new create a
 /// new thread to track events. 
 #[derive(Debug, Clone)]
 pub struct PlayerEventsStream {
-    // Identity is used because we cannot send player across threads or tasks
-    identity: String,
-    sender: Sender<Event>,
-    reciever: Receiver<Event>,
-    wakers: (Sender<Waker>, Receiver<Waker>),
+    // A handle is used because we cannot send player across threads or tasks
+    handle: PlayerHandle,
+    reciever: WakingReceiver<PlayerEvent>,
 }
 
 impl PlayerEventsStream {
     /// Creates a new [`PlayerEventsStream`] to track the changes of a player. This function will
     /// spawn a new thread that listens for changes and sends them to the stream and any stream
-    /// cloned from it. The thread only closes once the player has quit.
+    /// cloned from it. The thread only closes once the player has quit. If the connection to
+    /// DBus is lost, the thread reconnects and sends [`PlayerEvent::Reconnected`].
     pub fn new(player: &Player) -> PlayerEventsStream {
-        let (s, r) = unbounded();
-        let (wake_send, wake_reciev) = unbounded();
-        let streamer = PlayerEventsStream {identity: player.identity().to_string(), sender: s, reciever: r, wakers: (wake_send, wake_reciev)};
-        let stream_clone = streamer.clone();
-        thread::spawn(move || stream_clone.events_listener());
-        return streamer;
-    }
-
-    fn events_listener(self) {
-        let finder = match PlayerFinder::new() {
-            Ok(x) => x,
-            Err(_) => {
-                self.sender.try_send(Event::PlayerShutDown).unwrap();
-                self.sender.close();
-                return;
-            },
-        };
-        let player = finder.find_by_name(&self.identity).unwrap();
-        let events = player.events().unwrap();
-        
-        for event in events {
-            self.sender.try_send(match event.as_ref().unwrap() {
-                Event::PlayerShutDown => Event::PlayerShutDown,
-                Event::Paused => Event::Paused,
-                Event::Playing => Event::Playing,
-                Event::Stopped => Event::Stopped,
-                Event::LoopingChanged(status) => Event::LoopingChanged(*status),
-                Event::ShuffleToggled(x) => Event::ShuffleToggled(*x),
-                Event::VolumeChanged(x) => Event::VolumeChanged(*x),
-                Event::PlaybackRateChanged(x) => Event::PlaybackRateChanged(*x),
-                Event::TrackChanged(x) => Event::TrackChanged(x.clone()),
-                Event::Seeked { position_in_us } => Event::Seeked { position_in_us: *position_in_us },
-                Event::TrackAdded(x) => Event::TrackAdded(x.clone()),
-                Event::TrackRemoved(x) => Event::TrackRemoved(x.clone()),
-                Event::TrackMetadataChanged { old_id, new_id } => Event::TrackMetadataChanged { old_id: old_id.clone(), new_id: new_id.clone() },
-                Event::TrackListReplaced => Event::TrackListReplaced,
-            }).unwrap();
-            if matches!(event.unwrap(), Event::PlayerShutDown) {
-                self.sender.close();
+        return PlayerEventsStream::with_bus(player, BusConfig::Session);
+    }
+
+    /// Creates a new [`PlayerEventsStream`] for a player on the given bus. `bus` must be the bus
+    /// that `player` was found on.
+    pub fn with_bus(player: &Player, bus: BusConfig) -> PlayerEventsStream {
+        let (sender, reciever) = waking_channel();
+        let handle = PlayerHandle::with_bus(player, bus);
+        let listener_handle = handle.clone();
+        let properties_sender = sender.clone();
+        thread::spawn(move || PlayerEventsStream::events_listener(listener_handle, sender));
+        let listener_handle = handle.clone();
+        thread::spawn(move || PlayerEventsStream::properties_listener(listener_handle, properties_sender));
+        return PlayerEventsStream { handle, reciever };
+    }
+
+    /// Listens for the property changes that [`mpris::PlayerEvents`] does not report. Stops once
+    /// the player quits or [`events_listener`](Self::events_listener) has closed the channel.
+    fn properties_listener(handle: PlayerHandle, sender: WakingSender<PlayerEvent>) {
+        let mut backoff = Backoff::default();
+        loop {
+            let signals = match SignalListener::new(&handle) {
+                Ok(x) => x,
+                Err(_) if backoff.attempts() < PLAYER_LOOKUP_ATTEMPTS => {
+                    thread::sleep(backoff.next_delay());
+                    continue;
+                },
+                Err(_) => return,
+            };
+            backoff.reset();
+            for signal in signals {
+                let events = match signal {
+                    Signal::PropertiesChanged { interface, changed, .. } if interface == ROOT_INTERFACE => {
+                        RootEvent::from_changed(&changed).into_iter().map(PlayerEvent::Root).collect::<Vec<PlayerEvent>>()
+                    },
+                    Signal::PlayerQuit => return,
+                    _ => continue,
+                };
+                for event in events {
+                    if !sender.send(event) {
+                        return;
+                    }
+                }
             }
+            // The connection was lost, try again after a while
+            thread::sleep(backoff.next_delay());
+        }
+    }
 
-            loop {
-                match self.wakers.1.try_recv() {
-                    Ok(waker) => {
-                        waker.wake_by_ref();
+    fn events_listener(handle: PlayerHandle, sender: WakingSender<PlayerEvent>) {
+        let mut backoff = Backoff::default();
+        let mut reconnecting = false;
+        loop {
+            let finder = match handle.bus().finder() {
+                Ok(x) => x,
+                Err(_) if reconnecting => {
+                    thread::sleep(backoff.next_delay());
+                    continue;
+                },
+                Err(_) => break,
+            };
+            let player = match finder.find_by_name(handle.identity()) {
+                Ok(x) => x,
+                Err(FindingError::NoPlayerFound) if reconnecting && backoff.attempts() < PLAYER_LOOKUP_ATTEMPTS => {
+                    thread::sleep(backoff.next_delay());
+                    continue;
+                },
+                Err(FindingError::DBusError(_)) if reconnecting => {
+                    thread::sleep(backoff.next_delay());
+                    continue;
+                },
+                Err(_) => break,
+            };
+            let events = match player.events() {
+                Ok(x) => x,
+                Err(_) if reconnecting => {
+                    thread::sleep(backoff.next_delay());
+                    continue;
+                },
+                Err(_) => break,
+            };
+
+            let quirks = quirks_for(&player);
+
+            if reconnecting {
+                backoff.reset();
+                let mut state = vec![PlayerEvent::Reconnected];
+                state.extend(current_state(&player).into_iter().map(|x| PlayerEvent::Player(quirks.normalize_event(x))));
+                for event in state {
+                    if !sender.send(event) {
+                        return;
                     }
-                    _ => break,
                 }
             }
+
+            let mut last_track: Option<Metadata> = None;
+            for event in events {
+                // An error here usually means the connection is gone, which is checked below
+                let event = match event {
+                    Ok(x) => quirks.normalize_event(x),
+                    Err(_) => break,
+                };
+                let event = match (event, quirks.metadata_settle) {
+                    (Event::TrackChanged(metadata), Some(settle)) => {
+                        // Wait for the rest of the metadata, then send all of it at once
+                        thread::sleep(settle);
+                        let metadata = player.get_metadata().map(|x| quirks.normalize_metadata(x)).unwrap_or(metadata);
+                        if last_track.as_ref().map_or(false, |x| same_track(x, &metadata)) {
+                            continue;
+                        }
+                        last_track = Some(metadata.clone());
+                        Event::TrackChanged(metadata)
+                    },
+                    (event, _) => event,
+                };
+                let shut_down = matches!(event, Event::PlayerShutDown);
+                if !sender.send(PlayerEvent::Player(event)) {
+                    return;
+                }
+                if shut_down {
+                    sender.close();
+                    return;
+                }
+            }
+
+            // The events ended without the player shutting down. Either the player is gone or
+            // the connection to DBus was lost, the lookup at the top of the loop tells them apart.
+            reconnecting = true;
+            thread::sleep(backoff.next_delay());
         }
+
+        sender.send(PlayerEvent::Player(Event::PlayerShutDown));
+        sender.close();
     }
 
     /// Access to the reciever used to send Events around.
-    pub fn get_reciever(&self) -> Receiver<Event> {
-        self.reciever.clone()
+    pub fn get_reciever(&self) -> Receiver<PlayerEvent> {
+        self.reciever.reciever()
+    }
+
+    /// The handle of the player this stream tracks. Use it to control the player or to build
+    /// other streams for it.
+    pub fn handle(&self) -> &PlayerHandle {
+        &self.handle
     }
 }
 
 impl Stream for PlayerEventsStream {
-    type Item = Event;
+    type Item = PlayerEvent;
 
     fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
-        let reciever = self.reciever.clone();
-        self.wakers.0.try_send(cx.waker().clone()).unwrap();
-        let event = reciever.try_recv();
-        match event {
-            Ok(event) => {
-                cx.waker().wake_by_ref();
-                Poll::Ready(Some(event))
-            },
-            Err(TryRecvError::Empty) => Poll::Pending,
-            Err(TryRecvError::Closed) => Poll::Ready(None),
+        self.reciever.poll_recv(cx)
+    }
+}
+
+/// Items of a [`MergedEventsStream`].
+#[derive(Debug)]
+pub enum MergedEvent {
+    /// A player was found. Its events follow as [`MergedEvent::Event`].
+    Appeared(PlayerHandle),
+    /// An event of the player. The last event of every player is
+    /// [`Event::PlayerShutDown`](mpris::Event::PlayerShutDown).
+    Event(PlayerHandle, PlayerEvent),
+}
+
+/// The events of every player on a bus, in one stream. Makes a new thread that looks for
+/// players the same way [`crate::stream_players`] does, and a [`PlayerEventsStream`] for each
+/// one it finds. Created by calling [`crate::stream_all_events`].
+#[derive(Debug, Clone)]
+pub struct MergedEventsStream {
+    reciever: WakingReceiver<MergedEvent>,
+}
+
+impl MergedEventsStream {
+    /// Creates a new [`MergedEventsStream`] for the players on the session bus. Every
+    /// `retry_delay` milliseconds it will look for new players.
+    pub fn new(retry_delay: u64) -> Self {
+        return MergedEventsStream::with_bus(retry_delay, BusConfig::Session);
+    }
+
+    /// Creates a new [`MergedEventsStream`] for the players on the given bus.
+    pub fn with_bus(retry_delay: u64, bus: BusConfig) -> Self {
+        let (sender, reciever) = waking_channel();
+        thread::spawn(move || task::block_on(MergedEventsStream::players_listener(retry_delay, bus, sender)));
+        return MergedEventsStream { reciever };
+    }
+
+    /// Starts following every player that appears. Stops once the stream is dropped and
+    /// another player appears, or when the connection to DBus is lost for good.
+    async fn players_listener(retry_delay: u64, bus: BusConfig, sender: WakingSender<MergedEvent>) {
+        let following = Arc::new(Mutex::new(HashSet::new()));
+        let mut players = TryPlayerStream::with_bus(retry_delay, bus.clone());
+        while let Some(found) = players.next().await {
+            // A player that quits while being looked up shouldn't stop the others from being followed
+            let player = match found {
+                Ok(player) => player,
+                Err(_) => continue,
+            };
+            let bus_name = player.bus_name().to_string();
+            // Players are yielded again after reconnecting, their streams carry on by themselves
+            if !following.lock().unwrap().insert(bus_name.clone()) {
+                continue;
+            }
+            let events = PlayerEventsStream::with_bus(&player, bus.clone());
+            if !sender.send(MergedEvent::Appeared(events.handle().clone())) {
+                break;
+            }
+            task::spawn(MergedEventsStream::forward(events, sender.clone(), following.clone()));
         }
+        sender.close();
+    }
+
+    async fn forward(mut events: PlayerEventsStream, sender: WakingSender<MergedEvent>, following: Arc<Mutex<HashSet<String>>>) {
+        let handle = events.handle().clone();
+        while let Some(event) = events.next().await {
+            if !sender.send(MergedEvent::Event(handle.clone(), event)) {
+                break;
+            }
+        }
+        following.lock().unwrap().remove(handle.bus_name());
+    }
+
+    /// Access to the reciever used to send events around.
+    pub fn get_reciever(&self) -> Receiver<MergedEvent> {
+        self.reciever.reciever()
+    }
+}
+
+impl Stream for MergedEventsStream {
+    type Item = MergedEvent;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
+        self.reciever.poll_recv(cx)
+    }
+}
+
+/// Whether two versions of metadata describe the same track, as far as
+/// [`Event::TrackChanged`] is concerned.
+fn same_track(a: &Metadata, b: &Metadata) -> bool {
+    a.track_id() == b.track_id() && a.title() == b.title() && a.artists() == b.artists() && a.url() == b.url()
+}
+
+/// Reads the current state of the player as events, used to catch up after reconnecting.
+fn current_state(player: &Player) -> Vec<Event> {
+    let mut events = vec![];
+    if let Ok(metadata) = player.get_metadata() {
+        events.push(Event::TrackChanged(metadata));
+    }
+    if let Ok(status) = player.get_playback_status() {
+        events.push(match status {
+            PlaybackStatus::Playing => Event::Playing,
+            PlaybackStatus::Paused => Event::Paused,
+            PlaybackStatus::Stopped => Event::Stopped,
+        });
+    }
+    if let Ok(Some(status)) = player.checked_get_loop_status() {
+        events.push(Event::LoopingChanged(status));
+    }
+    if let Ok(Some(shuffle)) = player.checked_get_shuffle() {
+        events.push(Event::ShuffleToggled(shuffle));
+    }
+    if let Ok(Some(volume)) = player.checked_get_volume() {
+        events.push(Event::VolumeChanged(volume));
+    }
+    if let Ok(Some(rate)) = player.checked_get_playback_rate() {
+        events.push(Event::PlaybackRateChanged(rate));
     }
+    return events;
 }
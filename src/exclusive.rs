@@ -0,0 +1,181 @@
+//! [`ExclusivePlayback`] keeps only one player playing at a time, pausing the others whenever
+//! one starts.
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use async_std::stream::StreamExt;
+use mpris::{Event, PlaybackStatus};
+
+use crate::{connection::BusConfig, events::{MergedEvent, MergedEventsStream, PlayerEvent}, handle::PlayerHandle};
+
+/// Which players [`ExclusivePlayback`] manages and how. Players are named by their identity,
+/// ignoring case.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExclusivePolicy {
+    /// Resume the players that were paused once the player that paused them pauses or stops.
+    pub resume: bool,
+    /// A player that was just paused or resumed by the service is left alone for this long, so
+    /// players reacting to each other can't start a loop.
+    pub cooldown: Duration,
+    /// If set, only these players are managed. Every other player is ignored.
+    pub allow: Option<Vec<String>>,
+    /// These players are never paused, such as video call apps. They still pause others when
+    /// they start playing.
+    pub never_pause: Vec<String>,
+}
+
+impl Default for ExclusivePolicy {
+    fn default() -> Self {
+        ExclusivePolicy { resume: false, cooldown: Duration::from_secs(2), allow: None, never_pause: vec![] }
+    }
+}
+
+impl ExclusivePolicy {
+    fn manages(&self, identity: &str) -> bool {
+        match &self.allow {
+            Some(allow) => allow.iter().any(|x| x.eq_ignore_ascii_case(identity)),
+            None => true,
+        }
+    }
+
+    fn may_pause(&self, identity: &str) -> bool {
+        self.manages(identity) && !self.never_pause.iter().any(|x| x.eq_ignore_ascii_case(identity))
+    }
+}
+
+/// A player the service knows about, keyed by bus name.
+struct Managed {
+    handle: PlayerHandle,
+    status: PlaybackStatus,
+    /// When the service last paused or resumed the player.
+    touched_at: Option<Instant>,
+    /// The bus name of the player that caused this one to be paused.
+    paused_by: Option<String>,
+}
+
+impl Managed {
+    fn cooling_down(&self, cooldown: Duration) -> bool {
+        self.touched_at.map_or(false, |x| x.elapsed() < cooldown)
+    }
+}
+
+/// Pauses every other playing player when one starts playing, and optionally resumes them when
+/// it stops. Clones share the same state.
+#[derive(Clone)]
+pub struct ExclusivePlayback {
+    policy: Arc<ExclusivePolicy>,
+    players: Arc<Mutex<HashMap<String, Managed>>>,
+}
+
+impl ExclusivePlayback {
+    /// Creates a new [`ExclusivePlayback`]. Nothing happens until [`run`](Self::run) is called.
+    pub fn new(policy: ExclusivePolicy) -> Self {
+        ExclusivePlayback { policy: Arc::new(policy), players: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// The policy the service follows.
+    pub fn policy(&self) -> &ExclusivePolicy {
+        &self.policy
+    }
+
+    /// Manages every player on `bus` until the connection to DBus is lost for good. Players are
+    /// picked up as they appear.
+    pub async fn run(&self, bus: BusConfig, retry_delay: u64) {
+        let mut events = MergedEventsStream::with_bus(retry_delay, bus);
+        while let Some(event) = events.next().await {
+            match event {
+                MergedEvent::Appeared(handle) => self.appeared(handle).await,
+                MergedEvent::Event(handle, PlayerEvent::Player(Event::Playing)) => self.started(handle.bus_name()).await,
+                MergedEvent::Event(handle, PlayerEvent::Player(Event::Paused)) => self.stopped(handle.bus_name(), PlaybackStatus::Paused).await,
+                MergedEvent::Event(handle, PlayerEvent::Player(Event::Stopped)) => self.stopped(handle.bus_name(), PlaybackStatus::Stopped).await,
+                MergedEvent::Event(handle, PlayerEvent::Player(Event::PlayerShutDown)) => {
+                    self.stopped(handle.bus_name(), PlaybackStatus::Stopped).await;
+                    self.players.lock().unwrap().remove(handle.bus_name());
+                },
+                MergedEvent::Event(_, _) => {},
+            }
+        }
+    }
+
+    async fn appeared(&self, handle: PlayerHandle) {
+        if !self.policy.manages(handle.identity()) {
+            return;
+        }
+        let status = handle.run(|player| player.get_playback_status()).await.unwrap_or(PlaybackStatus::Stopped);
+        let bus_name = handle.bus_name().to_string();
+        self.players.lock().unwrap().insert(bus_name, Managed { handle, status, touched_at: None, paused_by: None });
+    }
+
+    /// The player `bus_name` started playing: pause the others.
+    async fn started(&self, bus_name: &str) {
+        let to_pause = {
+            let mut players = self.players.lock().unwrap();
+            let started = match players.get_mut(bus_name) {
+                Some(x) => x,
+                None => return,
+            };
+            started.status = PlaybackStatus::Playing;
+            // Playing again because of the service, or bouncing back right after being paused
+            if started.cooling_down(self.policy.cooldown) {
+                return;
+            }
+            started.paused_by = None;
+            players.iter()
+                .filter(|(key, x)| key.as_str() != bus_name && x.status == PlaybackStatus::Playing)
+                .filter(|(_, x)| self.policy.may_pause(x.handle.identity()) && !x.cooling_down(self.policy.cooldown))
+                .map(|(key, x)| (key.clone(), x.handle.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        for (key, handle) in to_pause {
+            if handle.pause().await.is_err() {
+                continue;
+            }
+            if let Some(paused) = self.players.lock().unwrap().get_mut(&key) {
+                paused.status = PlaybackStatus::Paused;
+                paused.touched_at = Some(Instant::now());
+                paused.paused_by = Some(bus_name.to_string());
+            }
+        }
+    }
+
+    /// The player `bus_name` paused, stopped or quit: resume the players it paused.
+    async fn stopped(&self, bus_name: &str, status: PlaybackStatus) {
+        let to_resume = {
+            let mut players = self.players.lock().unwrap();
+            let stopped = match players.get_mut(bus_name) {
+                Some(x) => x,
+                None => return,
+            };
+            stopped.status = status;
+            // Paused by the service, which is not a reason to resume anything
+            if !self.policy.resume || stopped.cooling_down(self.policy.cooldown) {
+                return;
+            }
+            let to_resume = players.iter()
+                .filter(|(_, x)| x.paused_by.as_deref() == Some(bus_name) && x.status == PlaybackStatus::Paused)
+                .map(|(key, x)| (key.clone(), x.handle.clone()))
+                .collect::<Vec<_>>();
+            for x in players.values_mut() {
+                if x.paused_by.as_deref() == Some(bus_name) {
+                    x.paused_by = None;
+                }
+            }
+            to_resume
+        };
+
+        for (key, handle) in to_resume {
+            // Marked before playing, so the Playing event that follows is not taken as a start
+            if let Some(resumed) = self.players.lock().unwrap().get_mut(&key) {
+                resumed.touched_at = Some(Instant::now());
+            }
+            let _ = handle.play().await;
+        }
+    }
+}
+
+impl std::fmt::Debug for ExclusivePlayback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExclusivePlayback").field("policy", &self.policy).field("players", &self.players.lock().unwrap().len()).finish()
+    }
+}
@@ -0,0 +1,198 @@
+//! Smooth volume changes. [`PlayerHandle::fade_to`] ramps the volume of a player over time, and
+//! [`PlayerHandle::fade_in`] and [`PlayerHandle::fade_out_and_pause`] build on it.
+
+use std::{future::Future, pin::Pin, sync::{atomic::{AtomicBool, Ordering}, Arc}, task::{Context, Poll}, time::{Duration, Instant}};
+
+use async_std::task;
+
+use crate::{error::Error, handle::PlayerHandle};
+
+/// The shortest time between two volume changes of a fade.
+const MIN_STEP_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How far the volume of the player may be from what the fade set before it counts as the user
+/// changing it. Players round the volume, some to whole percents.
+const VOLUME_TOLERANCE: f64 = 0.02;
+
+/// The shape of a fade, mapping how far along the fade is to how far the volume has moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FadeCurve {
+    /// The volume changes at a constant speed.
+    #[default]
+    Linear,
+    /// Starts slow and speeds up.
+    EaseIn,
+    /// Starts fast and slows down.
+    EaseOut,
+    /// Slow at both ends, fast in the middle.
+    SCurve,
+}
+
+impl FadeCurve {
+    /// Maps `t` from 0.0 to 1.0 onto the same range.
+    pub fn apply(&self, t: f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => t,
+            FadeCurve::EaseIn => t * t,
+            FadeCurve::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            FadeCurve::SCurve => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+/// How a [`Fade`] ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FadeOutcome {
+    /// The target volume was reached.
+    Completed,
+    /// [`FadeCanceller::cancel`] was called. The volume is left where the fade was.
+    Cancelled,
+    /// Someone else changed the volume during the fade. Contains the volume they set.
+    Interrupted(f64),
+}
+
+/// Cancels the [`Fade`] it was taken from, even from another task.
+#[derive(Debug, Clone)]
+pub struct FadeCanceller {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl FadeCanceller {
+    /// Stops the fade before its next step.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A running fade, created by [`PlayerHandle::fade_to`] and friends. Resolves once the fade has
+/// ended. Dropping it stops the fade as well.
+pub struct Fade {
+    future: Pin<Box<dyn Future<Output = Result<FadeOutcome, Error>> + Send>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Fade {
+    fn new<F>(cancelled: Arc<AtomicBool>, future: F) -> Self
+    where
+        F: Future<Output = Result<FadeOutcome, Error>> + Send + 'static,
+    {
+        Fade { future: Box::pin(future), cancelled }
+    }
+
+    /// Returns a [`FadeCanceller`] for this fade.
+    pub fn canceller(&self) -> FadeCanceller {
+        FadeCanceller { cancelled: self.cancelled.clone() }
+    }
+}
+
+impl std::fmt::Debug for Fade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Fade").field("cancelled", &self.cancelled).finish()
+    }
+}
+
+impl Future for Fade {
+    type Output = Result<FadeOutcome, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+impl PlayerHandle {
+    /// Moves the volume of the player to `volume` over `duration`, following `curve`. Volumes
+    /// are in the 0.0-1.0 range, see [`Quirks::volume_scale`](crate::quirks::Quirks::volume_scale).
+    ///
+    /// Before every step the volume is read back, and the fade stops with
+    /// [`FadeOutcome::Interrupted`] if it is not what the fade last set. Steps are never closer
+    /// together than it takes the player to apply one, so slow players get fewer, larger steps
+    /// instead of falling behind.
+    pub fn fade_to(&self, volume: f64, duration: Duration, curve: FadeCurve) -> Fade {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handle = self.clone();
+        let flag = cancelled.clone();
+        return Fade::new(cancelled, async move { fade(&handle, volume, duration, curve, &flag).await });
+    }
+
+    /// Sets the volume to 0, starts playing and fades up to `volume`.
+    pub fn fade_in(&self, volume: f64, duration: Duration, curve: FadeCurve) -> Fade {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handle = self.clone();
+        let flag = cancelled.clone();
+        return Fade::new(cancelled, async move {
+            handle.run(|player| {
+                player.set_volume(0.0)?;
+                player.play()
+            }).await?;
+            fade(&handle, volume, duration, curve, &flag).await
+        });
+    }
+
+    /// Fades the volume down to 0 and pauses. The volume is then put back to what it was, so
+    /// the next time playback starts it is not silent. Nothing is paused or restored if the
+    /// fade is cancelled or interrupted.
+    pub fn fade_out_and_pause(&self, duration: Duration, curve: FadeCurve) -> Fade {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handle = self.clone();
+        let flag = cancelled.clone();
+        return Fade::new(cancelled, async move {
+            let original = handle.run(|player| player.get_volume()).await?;
+            let outcome = fade(&handle, 0.0, duration, curve, &flag).await?;
+            if outcome != FadeOutcome::Completed {
+                return Ok(outcome);
+            }
+            handle.run(move |player| {
+                player.pause()?;
+                player.set_volume(original)
+            }).await?;
+            Ok(outcome)
+        });
+    }
+}
+
+async fn fade(handle: &PlayerHandle, target: f64, duration: Duration, curve: FadeCurve, cancelled: &AtomicBool) -> Result<FadeOutcome, Error> {
+    // Work in the 0.0-1.0 range, whatever range the player uses
+    let quirks = handle.quirks().await?;
+    let start = quirks.normalize_volume(handle.run(|player| player.get_volume()).await?);
+    let started_at = Instant::now();
+    // The player may not have applied the previous write yet when the next step reads the
+    // volume back, so both of the last two values count as ours
+    let mut written = (start, start);
+
+    loop {
+        if cancelled.load(Ordering::SeqCst) {
+            return Ok(FadeOutcome::Cancelled);
+        }
+
+        let t = match duration.is_zero() {
+            true => 1.0,
+            false => started_at.elapsed().as_secs_f64() / duration.as_secs_f64(),
+        };
+        let volume = start + (target - start) * curve.apply(t);
+        let expected = written;
+
+        let step_started = Instant::now();
+        let step_quirks = quirks.clone();
+        let current = handle.run(move |player| {
+            let current = step_quirks.normalize_volume(player.get_volume()?);
+            if is_ours(current, expected) {
+                player.set_volume(step_quirks.denormalize_volume(volume))?;
+            }
+            Ok::<f64, mpris::DBusError>(current)
+        }).await?;
+        if !is_ours(current, expected) {
+            return Ok(FadeOutcome::Interrupted(current));
+        }
+        written = (written.1, volume);
+
+        if t >= 1.0 {
+            return Ok(FadeOutcome::Completed);
+        }
+        task::sleep(step_started.elapsed().max(MIN_STEP_INTERVAL)).await;
+    }
+}
+
+fn is_ours(current: f64, written: (f64, f64)) -> bool {
+    (current - written.0).abs() <= VOLUME_TOLERANCE || (current - written.1).abs() <= VOLUME_TOLERANCE
+}
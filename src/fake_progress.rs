@@ -2,11 +2,13 @@
 //! Progress.
 use std::time::{Instant, Duration};
 
-use mpris::{LoopStatus, PlaybackStatus, Metadata, Progress};
+use mpris::{DBusError, LoopStatus, PlaybackStatus, Metadata, Player, Progress};
+
+use crate::sanity::PositionReliability;
 
 /// Used Because Cloning Progress is impossible, making [`crate::progress::ProgressStream`]
 /// impossible for me to implement
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct ProgressClone {
     pub(crate) metadata: Metadata,
     pub(crate) playback_status: PlaybackStatus,
@@ -19,6 +21,7 @@ pub struct ProgressClone {
     pub(crate) position: Duration,
     pub(crate) rate: f64,
     pub(crate) current_volume: f64,
+    pub(crate) reliability: PositionReliability,
 }
 
 impl ProgressClone {
@@ -32,8 +35,41 @@ impl ProgressClone {
             position: progress.position(),
             rate: progress.playback_rate(),
             current_volume: progress.current_volume(),
+            reliability: PositionReliability::Unknown,
+        }
+    }
+
+    /// Creates progress without reading a player, for tests.
+    #[cfg(test)]
+    pub(crate) fn from_parts(metadata: Metadata, playback_status: PlaybackStatus, position: Duration, instant: Instant) -> Self {
+        ProgressClone {
+            metadata,
+            playback_status,
+            shuffle: false,
+            loop_status: LoopStatus::None,
+            instant,
+            position,
+            rate: 1.0,
+            current_volume: 1.0,
+            reliability: PositionReliability::Unknown,
         }
     }
+
+    /// Reads the progress straight from the player, the same way [`mpris::ProgressTracker`] does.
+    pub(crate) fn read(player: &Player) -> Result<Self, DBusError> {
+        Ok(ProgressClone {
+            metadata: player.get_metadata()?,
+            playback_status: player.get_playback_status()?,
+            shuffle: player.checked_get_shuffle()?.unwrap_or(false),
+            loop_status: player.checked_get_loop_status()?.unwrap_or(LoopStatus::None),
+            rate: player.checked_get_playback_rate()?.unwrap_or(1.0),
+            position: player.checked_get_position()?.unwrap_or_else(|| Duration::new(0, 0)),
+            current_volume: player.checked_get_volume()?.unwrap_or(1.0),
+            instant: Instant::now(),
+            reliability: PositionReliability::Unknown,
+        })
+    }
+
     /// The track metadata at the point in time that this Progress was constructed.
     pub fn metadata(&self) -> &Metadata {
         &self.metadata
@@ -71,12 +107,8 @@ impl ProgressClone {
     /// determined.
     ///
     /// **Note:** Some players might not support this and will return a bad position. Spotify is
-    /// one such example. There is no reliable way of detecting problematic players, so it will be
-    /// up to your client to check for this.
-    ///
-    /// One way of doing this is to query the [`initial_position`](Self::initial_position) for two measures with the
-    /// [`PlaybackStatus::Playing`] and if both are `0`, then it is likely that this client does not
-    /// support positions.
+    /// one such example. [`crate::progress::ProgressStream`] checks for this, see
+    /// [`position_reliability`](Self::position_reliability).
     pub fn position(&self) -> Duration {
         self.position + self.elapsed()
     }
@@ -111,6 +143,38 @@ impl ProgressClone {
         self.current_volume
     }
 
+    /// Whether the position of the player can be trusted, as far as
+    /// [`PositionSanity`](crate::sanity::PositionSanity) could tell when this was created.
+    pub fn position_reliability(&self) -> PositionReliability {
+        self.reliability
+    }
+
+    /// How long until the track reaches `position`, assuming it plays on at the current rate
+    /// from the current [`position`](Self::position). Returns [`None`] if the position has
+    /// already been passed or the track is not moving forwards.
+    pub fn time_until(&self, position: Duration) -> Option<Duration> {
+        if self.rate <= 0.0 {
+            return None;
+        }
+        let left = position.checked_sub(self.position())?;
+        return Some(Duration::from_secs_f64(left.as_secs_f64() / self.rate));
+    }
+
+    /// How long until the current track ends, see [`time_until`](Self::time_until). Returns
+    /// [`None`] if the length of the track is unknown.
+    pub fn time_left(&self) -> Option<Duration> {
+        self.time_until(self.length()?)
+    }
+
+    /// Something that identifies the current track, used to notice when it changes. This is the
+    /// track id, or the url for players that don't send one.
+    pub(crate) fn track_key(&self) -> Option<String> {
+        match self.metadata.track_id() {
+            Some(id) => Some(id.to_string()),
+            None => self.metadata.url().map(|x| x.to_string()),
+        }
+    }
+
     fn elapsed(&self) -> Duration {
         let elapsed_ms = match self.playback_status {
             PlaybackStatus::Playing => {
@@ -0,0 +1,227 @@
+//! [`Follower`] makes other players mirror what a leader player does: playing, pausing, seeking
+//! and changing tracks, with the position kept in sync.
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use async_std::{future, stream::StreamExt};
+use mpris::{Event, Metadata, PlaybackStatus, Player};
+
+use crate::{connection::BusConfig, error::Error, events::{PlayerEvent, PlayerEventsStream}, fake_progress::ProgressClone, handle::{PlayerHandle, PLAYER_INTERFACE}, quirks::NO_TRACK};
+
+/// How a [`Follower`] keeps its followers in sync.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FollowPolicy {
+    /// How far a follower may be from the leader before it is moved.
+    pub drift_tolerance: Duration,
+    /// How often the followers are checked against the leader when nothing happens.
+    pub check_interval: Duration,
+    /// Open the leader's track on followers that don't have it in their tracklist. A track is
+    /// opened at most once on each follower until the leader changes tracks.
+    pub open_missing: bool,
+}
+
+impl Default for FollowPolicy {
+    fn default() -> Self {
+        FollowPolicy { drift_tolerance: Duration::from_millis(500), check_interval: Duration::from_secs(5), open_missing: true }
+    }
+}
+
+/// Makes every follower do what the leader does. Tracks are matched by `xesam:url`: a follower
+/// goes to the track with the same url in its tracklist, or opens the url. Followers can be
+/// added and removed while running. Clones share the same followers.
+#[derive(Debug, Clone)]
+pub struct Follower {
+    events: PlayerEventsStream,
+    followers: Arc<Mutex<Vec<PlayerHandle>>>,
+    /// The url opened on each follower for the leader's current track, by bus name.
+    opened: Arc<Mutex<HashMap<String, String>>>,
+    policy: FollowPolicy,
+}
+
+impl Follower {
+    /// Creates a [`Follower`] led by `leader` on the session bus.
+    pub fn new(leader: &Player, policy: FollowPolicy) -> Self {
+        return Follower::with_bus(leader, BusConfig::Session, policy);
+    }
+
+    /// Creates a [`Follower`] led by `leader` on the given bus. `bus` must be the bus that
+    /// `leader` was found on.
+    pub fn with_bus(leader: &Player, bus: BusConfig, policy: FollowPolicy) -> Self {
+        Follower {
+            events: PlayerEventsStream::with_bus(leader, bus),
+            followers: Arc::new(Mutex::new(vec![])),
+            opened: Arc::new(Mutex::new(HashMap::new())),
+            policy,
+        }
+    }
+
+    /// The handle of the leader.
+    pub fn leader(&self) -> &PlayerHandle {
+        self.events.handle()
+    }
+
+    /// Adds a player that should follow the leader. It is brought in sync on the next change or
+    /// position check.
+    pub fn add_follower(&self, follower: PlayerHandle) {
+        let mut followers = self.followers.lock().unwrap();
+        if !followers.contains(&follower) {
+            followers.push(follower);
+        }
+    }
+
+    /// Stops the player with the bus name `bus_name` from following.
+    pub fn remove_follower(&self, bus_name: &str) {
+        self.followers.lock().unwrap().retain(|x| x.bus_name() != bus_name);
+        self.opened.lock().unwrap().remove(bus_name);
+    }
+
+    /// The players following the leader.
+    pub fn followers(&self) -> Vec<PlayerHandle> {
+        self.followers.lock().unwrap().clone()
+    }
+
+    /// Mirrors the leader until it quits. Followers that quit are removed.
+    pub async fn run(&self) {
+        let mut events = self.events.clone();
+        // Start from the same place
+        self.sync_all().await;
+        loop {
+            let event = match future::timeout(self.policy.check_interval, events.next()).await {
+                Ok(Some(x)) => x,
+                Ok(None) => return,
+                Err(_) => {
+                    self.sync_all().await;
+                    continue;
+                },
+            };
+            match event {
+                PlayerEvent::Player(Event::PlayerShutDown) => return,
+                PlayerEvent::Player(Event::Playing) => self.sync_all().await,
+                PlayerEvent::Player(Event::Paused) => self.each(|x| async move { x.pause().await }).await,
+                PlayerEvent::Player(Event::Stopped) => self.each(|x| async move { x.stop().await }).await,
+                PlayerEvent::Player(Event::Seeked { .. }) => self.sync_all().await,
+                PlayerEvent::Player(Event::TrackChanged(metadata)) => {
+                    // A new track may be opened again, even one that failed before
+                    self.opened.lock().unwrap().clear();
+                    let policy = self.policy.clone();
+                    self.each(|x| {
+                        let metadata = metadata.clone();
+                        let policy = policy.clone();
+                        let opened = self.opened.clone();
+                        async move { sync_track(&x, &metadata, &policy, &opened).await.map(|_| ()) }
+                    }).await;
+                },
+                PlayerEvent::Player(Event::PlaybackRateChanged(rate)) => {
+                    self.each(|x| async move { x.run(move |player| player.set_playback_rate(rate)).await }).await;
+                },
+                PlayerEvent::Reconnected => self.sync_all().await,
+                _ => {},
+            }
+        }
+    }
+
+    /// Runs `f` on every follower, removing those that quit.
+    async fn each<F, Fut>(&self, f: F)
+    where
+        F: Fn(PlayerHandle) -> Fut,
+        Fut: std::future::Future<Output = Result<(), Error>>,
+    {
+        for follower in self.followers() {
+            if let Err(Error::PlayerQuit(_)) = f(follower.clone()).await {
+                self.remove_follower(follower.bus_name());
+            }
+        }
+    }
+
+    /// Brings every follower to the leader's track, position and playback status.
+    async fn sync_all(&self) {
+        let leader = match self.leader().run(ProgressClone::read).await {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let policy = self.policy.clone();
+        self.each(|x| {
+            let leader = leader.clone();
+            let policy = policy.clone();
+            let opened = self.opened.clone();
+            async move { sync_follower(&x, &leader, &policy, &opened).await }
+        }).await;
+    }
+}
+
+async fn sync_follower(follower: &PlayerHandle, leader: &ProgressClone, policy: &FollowPolicy, opened: &Mutex<HashMap<String, String>>) -> Result<(), Error> {
+    if !sync_track(follower, leader.metadata(), policy, opened).await? {
+        return Ok(());
+    }
+    let current = follower.run(ProgressClone::read).await?;
+    if leader.playback_status() != PlaybackStatus::Stopped {
+        // Both positions are worked out for the current instant, so they can be compared
+        let (ahead, behind) = (leader.position().max(current.position()), leader.position().min(current.position()));
+        if ahead - behind > policy.drift_tolerance {
+            move_to(follower, leader.position()).await?;
+        }
+    }
+    if current.playback_status() != leader.playback_status() {
+        match leader.playback_status() {
+            PlaybackStatus::Playing => follower.play().await?,
+            PlaybackStatus::Paused => follower.pause().await?,
+            PlaybackStatus::Stopped => follower.stop().await?,
+        }
+    }
+    return Ok(());
+}
+
+/// Makes the follower play the leader's track. Returns false if the follower is not on it yet:
+/// the track has no url, the follower does not have it and may not open it, or it was already
+/// opened and has not started. `opened` keeps the url opened on each follower, so it is only
+/// opened once.
+async fn sync_track(follower: &PlayerHandle, metadata: &Metadata, policy: &FollowPolicy, opened: &Mutex<HashMap<String, String>>) -> Result<bool, Error> {
+    let url = match metadata.url() {
+        Some(x) => x.to_string(),
+        None => return Ok(false),
+    };
+    let wanted = url.clone();
+    let found = follower.run(move |player| {
+        if player.get_metadata()?.url() == Some(wanted.as_str()) {
+            return Ok::<bool, Error>(true);
+        }
+        if !player.supports_track_lists() {
+            return Ok(false);
+        }
+        let ids = player.get_track_list()?.ids().to_vec();
+        let track = player.get_tracks_metadata(&ids)?.into_iter().find(|x| x.url() == Some(wanted.as_str()));
+        return match track.and_then(|x| x.track_id()) {
+            Some(id) => {
+                player.go_to(&id)?;
+                Ok(true)
+            },
+            None => Ok(false),
+        };
+    }).await?;
+    if found || !policy.open_missing {
+        return Ok(found);
+    }
+    if opened.lock().unwrap().get(follower.bus_name()) == Some(&url) {
+        return Ok(false);
+    }
+    opened.lock().unwrap().insert(follower.bus_name().to_string(), url.clone());
+    follower.run_raw(move |path| {
+        let _: () = path.method_call(PLAYER_INTERFACE, "OpenUri", (url,))?;
+        Ok(())
+    }).await?;
+    // The position is synced on the next check, once the track has loaded
+    return Ok(false);
+}
+
+/// Moves the follower's current track to `position`.
+async fn move_to(follower: &PlayerHandle, position: Duration) -> Result<(), Error> {
+    return follower.run(move |player| {
+        match player.get_metadata()?.track_id() {
+            Some(id) if id.as_str() != NO_TRACK => player.set_position(id, &position),
+            _ => {
+                let current = player.get_position()?;
+                player.seek(position.as_micros() as i64 - current.as_micros() as i64)
+            },
+        }
+    }).await;
+}
@@ -0,0 +1,108 @@
+//! [`PlayerHandle`] is a [`Send`] reference to a player that async control methods are built on.
+
+use async_std::task;
+use dbus::ffidisp::{ConnPath, Connection};
+use mpris::{DBusError, FindingError, Player};
+
+use crate::{connection::BusConfig, error::Error};
+
+/// The object path every MPRIS player exposes its interfaces on.
+pub(crate) const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+
+/// The interface with the playback controls.
+pub(crate) const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/// Timeout for calls that go straight to DBus instead of through [`Player`].
+pub(crate) const DBUS_TIMEOUT_MS: i32 = 500;
+
+/// A reference to a player that can be sent across threads and tasks, unlike [`Player`]. Every
+/// call looks the player up again on a blocking thread, the same way the streams in this crate do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerHandle {
+    identity: String,
+    bus_name: String,
+    bus: BusConfig,
+}
+
+impl PlayerHandle {
+    /// Creates a handle for a player on the session bus.
+    pub fn new(player: &Player) -> Self {
+        return PlayerHandle::with_bus(player, BusConfig::Session);
+    }
+
+    /// Creates a handle for a player on the given bus. `bus` must be the bus that `player` was
+    /// found on.
+    pub fn with_bus(player: &Player, bus: BusConfig) -> Self {
+        return PlayerHandle { identity: player.identity().to_string(), bus_name: player.bus_name().to_string(), bus };
+    }
+
+    /// Creates a handle without looking at a player, for tests that never call it.
+    #[cfg(all(test, any(feature = "rules", feature = "scripting")))]
+    pub(crate) fn from_parts(identity: &str, bus_name: &str) -> Self {
+        return PlayerHandle { identity: identity.to_string(), bus_name: bus_name.to_string(), bus: BusConfig::Session };
+    }
+
+    /// Returns handles for every player currently on `bus`.
+    pub async fn find_all(bus: &BusConfig) -> Result<Vec<PlayerHandle>, Error> {
+        let bus = bus.clone();
+        return task::spawn_blocking(move || {
+            let finder = bus.finder().map_err(Error::Connection)?;
+            let players = match finder.find_all() {
+                Ok(x) => x,
+                Err(FindingError::NoPlayerFound) => vec![],
+                Err(FindingError::DBusError(e)) => return Err(Error::DBus(e)),
+            };
+            Ok(players.iter().map(|x| PlayerHandle::with_bus(x, bus.clone())).collect())
+        }).await;
+    }
+
+    /// The identity of the player, used to find it again.
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// The well known bus name of the player, such as `org.mpris.MediaPlayer2.vlc`.
+    pub fn bus_name(&self) -> &str {
+        &self.bus_name
+    }
+
+    /// The bus the player lives on.
+    pub fn bus(&self) -> &BusConfig {
+        &self.bus
+    }
+
+    /// Looks the player up on the current thread. Used by the listener threads.
+    pub(crate) fn find(&self) -> Result<Player, Error> {
+        let finder = self.bus.finder().map_err(Error::Connection)?;
+        return finder.find_by_name(&self.identity).map_err(|e| Error::from_finding(e, &self.identity));
+    }
+
+    /// Runs `f` with the player on a blocking thread and returns its result.
+    pub async fn run<T, E, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        E: Into<Error>,
+        F: FnOnce(&Player) -> Result<T, E> + Send + 'static,
+    {
+        let handle = self.clone();
+        return task::spawn_blocking(move || {
+            let player = handle.find()?;
+            f(&player).map_err(Into::into)
+        }).await;
+    }
+
+    /// Runs `f` on a blocking thread with a raw DBus connection to the player's MPRIS object.
+    /// Used for the interfaces that [`Player`] does not cover.
+    pub(crate) async fn run_raw<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ConnPath<'_, &Connection>) -> Result<T, dbus::Error> + Send + 'static,
+    {
+        let handle = self.clone();
+        return task::spawn_blocking(move || {
+            let connection = handle.bus.connect().map_err(Error::Connection)?;
+            let path = connection.with_path(handle.bus_name.as_str(), MPRIS_PATH, DBUS_TIMEOUT_MS);
+            f(&path).map_err(|e| Error::DBus(DBusError::from(e)))
+        }).await;
+    }
+}
@@ -0,0 +1,474 @@
+//! A listening history of every player, kept in an SQLite database. [`History::record_players`]
+//! fills it from the events of each player, and the query methods read it back.
+//!
+//! Needs the `history` feature.
+
+use std::{collections::HashSet, fmt, io::{self, Write}, path::Path, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use async_std::{stream::StreamExt, task};
+use mpris::Event;
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+
+use crate::{connection::BusConfig, error::Error, events::{PlayerEvent, PlayerEventsStream}, fake_progress::ProgressClone, handle::PlayerHandle, listen::{Listen, ListenEvent, ListenTracker}, quirks::quirks_for, try_stream_players_on};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS plays (
+        id INTEGER PRIMARY KEY,
+        player TEXT NOT NULL,
+        title TEXT,
+        album TEXT,
+        url TEXT,
+        track_id TEXT,
+        length_ms INTEGER,
+        started_at_ms INTEGER NOT NULL,
+        ended_at_ms INTEGER NOT NULL,
+        listened_ms INTEGER NOT NULL,
+        completed INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS play_artists (
+        play_id INTEGER NOT NULL REFERENCES plays(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        artist TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS plays_started_at ON plays(started_at_ms);
+    CREATE INDEX IF NOT EXISTS play_artists_artist ON play_artists(artist);
+";
+
+const SELECT_PLAYS: &str = "SELECT id, player, title, album, url, track_id, length_ms, started_at_ms, ended_at_ms, listened_ms, completed FROM plays";
+
+/// Errors from the history database or from exporting it.
+#[derive(Debug)]
+pub enum HistoryError {
+    /// The database could not be opened, read or written.
+    Database(rusqlite::Error),
+    /// Writing an export failed.
+    Io(io::Error),
+    /// Serializing an export failed.
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::Database(e) => write!(f, "History database error: {}", e),
+            HistoryError::Io(e) => write!(f, "Could not write history export: {}", e),
+            HistoryError::Json(e) => write!(f, "Could not serialize history: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HistoryError::Database(e) => Some(e),
+            HistoryError::Io(e) => Some(e),
+            HistoryError::Json(e) => Some(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for HistoryError {
+    fn from(e: rusqlite::Error) -> Self {
+        HistoryError::Database(e)
+    }
+}
+
+impl From<io::Error> for HistoryError {
+    fn from(e: io::Error) -> Self {
+        HistoryError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for HistoryError {
+    fn from(e: serde_json::Error) -> Self {
+        HistoryError::Json(e)
+    }
+}
+
+/// One track played by one player. Times are in milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistoryEntry {
+    id: i64,
+    player: String,
+    title: Option<String>,
+    artists: Vec<String>,
+    album: Option<String>,
+    url: Option<String>,
+    track_id: Option<String>,
+    length_ms: Option<u64>,
+    started_at_ms: u64,
+    ended_at_ms: u64,
+    listened_ms: u64,
+    completed: bool,
+}
+
+impl HistoryEntry {
+    /// The id of the entry in the database.
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    /// The identity of the player that played the track.
+    pub fn player(&self) -> &str {
+        &self.player
+    }
+
+    /// The title of the track.
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// The artists of the track.
+    pub fn artists(&self) -> &[String] {
+        &self.artists
+    }
+
+    /// The album of the track.
+    pub fn album(&self) -> Option<&str> {
+        self.album.as_deref()
+    }
+
+    /// The url of the track.
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// The track id the player used for the track.
+    pub fn track_id(&self) -> Option<&str> {
+        self.track_id.as_deref()
+    }
+
+    /// The length of the track.
+    pub fn length(&self) -> Option<Duration> {
+        self.length_ms.map(Duration::from_millis)
+    }
+
+    /// When the track started playing.
+    pub fn started_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.started_at_ms)
+    }
+
+    /// When the track stopped playing.
+    pub fn ended_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.ended_at_ms)
+    }
+
+    /// How long the track was listened to, see [`Listen::listened`].
+    pub fn listened(&self) -> Duration {
+        Duration::from_millis(self.listened_ms)
+    }
+
+    /// Whether the track played until the end. Otherwise it was skipped or stopped.
+    pub fn completed(&self) -> bool {
+        self.completed
+    }
+
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        Ok(HistoryEntry {
+            id: row.get(0)?,
+            player: row.get(1)?,
+            title: row.get(2)?,
+            artists: vec![],
+            album: row.get(3)?,
+            url: row.get(4)?,
+            track_id: row.get(5)?,
+            length_ms: row.get::<_, Option<i64>>(6)?.map(|x| x as u64),
+            started_at_ms: row.get::<_, i64>(7)? as u64,
+            ended_at_ms: row.get::<_, i64>(8)? as u64,
+            listened_ms: row.get::<_, i64>(9)? as u64,
+            completed: row.get(10)?,
+        })
+    }
+}
+
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+/// The listening history. Can be shared between tasks, calls block while the database is busy.
+#[derive(Debug)]
+pub struct History {
+    connection: Mutex<Connection>,
+}
+
+impl History {
+    /// Opens the history at `path`, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, HistoryError> {
+        return History::with_connection(Connection::open(path)?);
+    }
+
+    /// Opens a history that only lives in memory.
+    pub fn open_in_memory() -> Result<Self, HistoryError> {
+        return History::with_connection(Connection::open_in_memory()?);
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, HistoryError> {
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        connection.execute_batch(SCHEMA)?;
+        return Ok(History { connection: Mutex::new(connection) });
+    }
+
+    /// Adds a listen by the player with the identity `player`. Returns the id of the entry.
+    pub fn record(&self, player: &str, listen: &Listen) -> Result<i64, HistoryError> {
+        let metadata = listen.metadata();
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO plays (player, title, album, url, track_id, length_ms, started_at_ms, ended_at_ms, listened_ms, completed)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                player,
+                metadata.title(),
+                metadata.album_name(),
+                metadata.url(),
+                metadata.track_id().map(|x| x.to_string()),
+                listen.length().map(|x| x.as_millis() as i64),
+                millis(listen.started_at()),
+                millis(listen.ended_at()),
+                listen.listened().as_millis() as i64,
+                listen.completed(),
+            ],
+        )?;
+        let id = transaction.last_insert_rowid();
+        for (position, artist) in metadata.artists().unwrap_or_default().iter().enumerate() {
+            transaction.execute("INSERT INTO play_artists (play_id, position, artist) VALUES (?1, ?2, ?3)", params![id, position as i64, artist])?;
+        }
+        transaction.commit()?;
+        return Ok(id);
+    }
+
+    /// The most recent `limit` entries, newest first.
+    pub fn recent(&self, limit: usize) -> Result<Vec<HistoryEntry>, HistoryError> {
+        return self.query(&format!("{} ORDER BY started_at_ms DESC LIMIT ?1", SELECT_PLAYS), params![limit as i64]);
+    }
+
+    /// The most recent `limit` entries of the player with the identity `player`, newest first.
+    pub fn by_player(&self, player: &str, limit: usize) -> Result<Vec<HistoryEntry>, HistoryError> {
+        return self.query(&format!("{} WHERE player = ?1 ORDER BY started_at_ms DESC LIMIT ?2", SELECT_PLAYS), params![player, limit as i64]);
+    }
+
+    /// Every entry that started between `from` and `to`, oldest first.
+    pub fn between(&self, from: SystemTime, to: SystemTime) -> Result<Vec<HistoryEntry>, HistoryError> {
+        return self.query(&format!("{} WHERE started_at_ms >= ?1 AND started_at_ms < ?2 ORDER BY started_at_ms", SELECT_PLAYS), params![millis(from), millis(to)]);
+    }
+
+    /// The `limit` most played artists since `since`, or of all time, with how often each was
+    /// played.
+    pub fn top_artists(&self, since: Option<SystemTime>, limit: usize) -> Result<Vec<(String, u64)>, HistoryError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT play_artists.artist, COUNT(*) AS plays FROM play_artists
+             JOIN plays ON plays.id = play_artists.play_id
+             WHERE plays.started_at_ms >= ?1
+             GROUP BY play_artists.artist ORDER BY plays DESC, play_artists.artist LIMIT ?2",
+        )?;
+        let since = since.map(millis).unwrap_or(0);
+        let rows = statement.query_map(params![since, limit as i64], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64)))?;
+        return Ok(rows.collect::<Result<Vec<_>, _>>()?);
+    }
+
+    fn query(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<HistoryEntry>, HistoryError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(sql)?;
+        let mut entries = statement.query_map(params, HistoryEntry::from_row)?.collect::<Result<Vec<_>, _>>()?;
+
+        let mut artists = connection.prepare("SELECT artist FROM play_artists WHERE play_id = ?1 ORDER BY position")?;
+        for entry in &mut entries {
+            entry.artists = artists.query_map(params![entry.id], |row| row.get(0))?.collect::<Result<Vec<String>, _>>()?;
+        }
+        return Ok(entries);
+    }
+
+    /// Records every track played by every player on `bus`, until the connection to DBus is
+    /// lost for good. Players are picked up as they appear.
+    pub async fn record_players(self: Arc<Self>, bus: BusConfig, retry_delay: u64) {
+        let recording = Arc::new(Mutex::new(HashSet::new()));
+        let mut players = try_stream_players_on(bus.clone(), retry_delay);
+        while let Some(found) = players.next().await {
+            // A player that quits while being looked up shouldn't stop the others from being recorded
+            let player = match found {
+                Ok(player) => player,
+                Err(_) => continue,
+            };
+            // Players are yielded again after reconnecting, they only need one recorder. Several
+            // instances of a player share an identity but not a bus name.
+            if !recording.lock().unwrap().insert(player.bus_name().to_string()) {
+                continue;
+            }
+            let events = PlayerEventsStream::with_bus(&player, bus.clone());
+            task::spawn(History::record_player(self.clone(), player.identity().to_string(), events, recording.clone()));
+        }
+    }
+
+    /// Reads the progress of the player whenever an event could have changed what is playing or
+    /// how far along it is.
+    async fn record_player(history: Arc<Self>, identity: String, mut events: PlayerEventsStream, recording: Arc<Mutex<HashSet<String>>>) {
+        let mut listens = ListenTracker::new();
+        // Whatever is playing already counts from now
+        let mut changed = true;
+        loop {
+            if changed {
+                if let Ok(progress) = read_progress(events.handle()).await {
+                    for event in listens.observe(&progress) {
+                        if let ListenEvent::Ended(listen) = event {
+                            History::record_blocking(&history, &identity, listen).await;
+                        }
+                    }
+                }
+            }
+            changed = match events.next().await {
+                Some(PlayerEvent::Player(Event::PlayerShutDown)) | None => break,
+                Some(PlayerEvent::Player(_)) | Some(PlayerEvent::Reconnected) => true,
+                Some(PlayerEvent::Root(_)) => false,
+            };
+        }
+        if let Some(listen) = listens.finish() {
+            History::record_blocking(&history, &identity, listen).await;
+        }
+        recording.lock().unwrap().remove(events.handle().bus_name());
+    }
+
+    async fn record_blocking(history: &Arc<Self>, identity: &str, listen: Listen) {
+        let history = history.clone();
+        let identity = identity.to_string();
+        let _ = task::spawn_blocking(move || history.record(&identity, &listen)).await;
+    }
+}
+
+async fn read_progress(handle: &PlayerHandle) -> Result<ProgressClone, Error> {
+    handle.run(|player| {
+        let mut progress = ProgressClone::read(player)?;
+        quirks_for(player).normalize_progress(&mut progress);
+        Ok::<ProgressClone, Error>(progress)
+    }).await
+}
+
+/// Writes `entries` as a JSON array.
+pub fn export_json(entries: &[HistoryEntry], writer: impl Write) -> Result<(), HistoryError> {
+    serde_json::to_writer_pretty(writer, entries)?;
+    return Ok(());
+}
+
+/// Writes `entries` as CSV with a header row. Artists are joined with `; `.
+pub fn export_csv(entries: &[HistoryEntry], mut writer: impl Write) -> Result<(), HistoryError> {
+    writeln!(writer, "id,player,title,artists,album,url,track_id,length_ms,started_at_ms,ended_at_ms,listened_ms,completed")?;
+    for entry in entries {
+        let fields = [
+            entry.id.to_string(),
+            csv_field(&entry.player),
+            csv_field(entry.title().unwrap_or_default()),
+            csv_field(&entry.artists.join("; ")),
+            csv_field(entry.album().unwrap_or_default()),
+            csv_field(entry.url().unwrap_or_default()),
+            csv_field(entry.track_id().unwrap_or_default()),
+            entry.length_ms.map(|x| x.to_string()).unwrap_or_default(),
+            entry.started_at_ms.to_string(),
+            entry.ended_at_ms.to_string(),
+            entry.listened_ms.to_string(),
+            entry.completed.to_string(),
+        ];
+        writeln!(writer, "{}", fields.join(","))?;
+    }
+    return Ok(());
+}
+
+/// Quotes a field if it contains anything CSV treats specially.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    return value.to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mpris::{Metadata, MetadataValue};
+
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn listen(title: &str, artists: &[&str], started_at: u64) -> Listen {
+        let mut fields = HashMap::new();
+        fields.insert("xesam:title".to_string(), MetadataValue::String(title.to_string()));
+        fields.insert("xesam:artist".to_string(), MetadataValue::Array(artists.iter().map(|x| MetadataValue::String(x.to_string())).collect()));
+        fields.insert("mpris:length".to_string(), MetadataValue::I64(180_000_000));
+        return Listen::from_parts(Metadata::from(fields), at(started_at), Duration::from_secs(90), Duration::from_secs(178));
+    }
+
+    fn history() -> History {
+        let history = History::open_in_memory().unwrap();
+        history.record("mpv", &listen("One", &["A", "B"], 100)).unwrap();
+        history.record("mpv", &listen("Two", &["B"], 200)).unwrap();
+        history.record("Spotify", &listen("Three", &["B", "C"], 300)).unwrap();
+        return history;
+    }
+
+    #[test]
+    fn records_listens() {
+        let history = history();
+        let entries = history.recent(10).unwrap();
+        let titles = entries.iter().map(|x| x.title().unwrap()).collect::<Vec<_>>();
+        assert_eq!(titles, vec!["Three", "Two", "One"]);
+        let one = &entries[2];
+        assert_eq!(one.player(), "mpv");
+        assert_eq!(one.artists(), ["A", "B"]);
+        assert_eq!(one.length(), Some(Duration::from_secs(180)));
+        assert_eq!(one.started_at(), at(100));
+        assert_eq!(one.ended_at(), at(190));
+        assert_eq!(one.listened(), Duration::from_secs(90));
+        assert!(one.completed());
+        assert_eq!(history.recent(1).unwrap()[0].title(), Some("Three"));
+        assert_eq!(history.by_player("mpv", 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reads_ranges_oldest_first() {
+        let entries = history().between(at(150), at(300)).unwrap();
+        assert_eq!(entries.iter().map(|x| x.title().unwrap()).collect::<Vec<_>>(), vec!["Two"]);
+    }
+
+    #[test]
+    fn counts_top_artists() {
+        let history = history();
+        let all_time = history.top_artists(None, 10).unwrap();
+        assert_eq!(all_time, vec![("B".to_string(), 3), ("A".to_string(), 1), ("C".to_string(), 1)]);
+        assert_eq!(history.top_artists(Some(at(200)), 1).unwrap(), vec![("B".to_string(), 2)]);
+    }
+
+    #[test]
+    fn exports_json() {
+        let entries = history().recent(1).unwrap();
+        let mut out = vec![];
+        export_json(&entries, &mut out).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value[0]["title"], "Three");
+        assert_eq!(value[0]["artists"], serde_json::json!(["B", "C"]));
+        assert_eq!(value[0]["started_at_ms"], 300_000);
+    }
+
+    #[test]
+    fn exports_csv() {
+        let history = History::open_in_memory().unwrap();
+        history.record("mpv", &listen("Hello, \"World\"\nAgain", &["A"], 100)).unwrap();
+        let mut out = vec![];
+        export_csv(&history.recent(10).unwrap(), &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let mut lines = csv.splitn(2, '\n');
+        assert_eq!(lines.next().unwrap(), "id,player,title,artists,album,url,track_id,length_ms,started_at_ms,ended_at_ms,listened_ms,completed");
+        assert_eq!(lines.next().unwrap(), "1,mpv,\"Hello, \"\"World\"\"\nAgain\",A,,,,180000,100000,190000,90000,true\n");
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+}
@@ -3,7 +3,8 @@
 //! Async version of the mpris crate. 
 //!
 //! Provides async versions of [`PlayerEvents`](mpris::PlayerEvents), [`PlayerFinder`](mpris::PlayerFinder),
-//! and [`ProgressTracker`](mpris::ProgressTracker).
+//! and [`ProgressTracker`](mpris::ProgressTracker), as well as a live mirror of the
+//! [`TrackList`](track_list::TrackListStream).
 //!
 //! # Get started 
 //! Easiest way to get started with mpris is using [`get_active_player`] and then using
@@ -13,19 +14,57 @@ pub mod player;
 pub mod events;
 pub mod progress;
 pub mod fake_progress;
+pub mod error;
+pub mod connection;
+pub mod handle;
+pub mod track_list;
+pub mod playlists;
+pub mod root;
+pub mod capabilities;
+pub mod fade;
+pub mod sleep_timer;
+pub mod position;
+pub mod ticker;
+pub mod sanity;
+pub mod quirks;
+pub mod listen;
+pub mod completion;
+pub mod ab_loop;
+pub mod exclusive;
+pub mod follow;
+#[cfg(feature = "scrobble")]
+pub mod scrobble;
+#[cfg(feature = "history")]
+pub mod history;
+#[cfg(feature = "bookmarks")]
+pub mod bookmarks;
+#[cfg(feature = "session")]
+pub mod session;
+#[cfg(feature = "rules")]
+pub mod rules;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+mod reconnect;
+mod signals;
+mod waker;
 pub use mpris::{Player, Progress, Metadata, TrackList, TrackID};
 
 use std::time::Duration;
-use crate::player::PlayerStream;
+use crate::{connection::BusConfig, events::MergedEventsStream, player::{PlayerStream, TryPlayerStream}};
 
 use async_std::task;
 
-use mpris::{DBusError, FindingError, PlayerFinder};
+use mpris::{DBusError, FindingError};
 
 /// Gets the most active player. If no player exists, this function will wait until one does.
-/// Based of off [`PlayerFinder::find_active`](PlayerFinder::find_active)
+/// Based of off [`PlayerFinder::find_active`](mpris::PlayerFinder::find_active)
 pub async fn get_active_player(retry_delay: u64) -> Result<Player, DBusError> {
-    let finder = match PlayerFinder::new() {
+    return get_active_player_on(&BusConfig::Session, retry_delay).await;
+}
+
+/// Same as [`get_active_player`], but looks for players on the given bus.
+pub async fn get_active_player_on(bus: &BusConfig, retry_delay: u64) -> Result<Player, DBusError> {
+    let finder = match bus.finder() {
         Ok(x) => x,
         Err(_) => return  Err(DBusError::Miscellaneous("Could not create player finder. Is DBus running?".to_string())),
     };
@@ -43,9 +82,14 @@ pub async fn get_active_player(retry_delay: u64) -> Result<Player, DBusError> {
 }
 
 /// Gets the first player. If no player exists, this function will wait until one does.
-/// Based of off [`PlayerFinder::find_first`](PlayerFinder::find_first)
+/// Based of off [`PlayerFinder::find_first`](mpris::PlayerFinder::find_first)
 pub async fn get_first_player(retry_delay: u64) -> Result<Player, DBusError> {
-    let finder = match PlayerFinder::new() {
+    return get_first_player_on(&BusConfig::Session, retry_delay).await;
+}
+
+/// Same as [`get_first_player`], but looks for players on the given bus.
+pub async fn get_first_player_on(bus: &BusConfig, retry_delay: u64) -> Result<Player, DBusError> {
+    let finder = match bus.finder() {
         Ok(x) => x,
         Err(_) => return  Err(DBusError::Miscellaneous("Could not create player finder. Is DBus running?".to_string())),
     };
@@ -64,9 +108,14 @@ pub async fn get_first_player(retry_delay: u64) -> Result<Player, DBusError> {
 
 /// Gets all of the avaliable players. If no player exists, this function will wait until one does.
 /// Every `retry_delay` milliseconds it will try for a new connection.
-/// Based of off [`PlayerFinder::find_all`](PlayerFinder::find_all)
+/// Based of off [`PlayerFinder::find_all`](mpris::PlayerFinder::find_all)
 pub async fn get_players(retry_delay: u64) -> Result<Vec<Player>, DBusError> {
-    let finder = match PlayerFinder::new() {
+    return get_players_on(&BusConfig::Session, retry_delay).await;
+}
+
+/// Same as [`get_players`], but looks for players on the given bus.
+pub async fn get_players_on(bus: &BusConfig, retry_delay: u64) -> Result<Vec<Player>, DBusError> {
+    let finder = match bus.finder() {
         Ok(x) => x,
         Err(_) => return  Err(DBusError::Miscellaneous("Could not create player finder. Is DBus running?".to_string())),
     };
@@ -88,3 +137,30 @@ pub async fn get_players(retry_delay: u64) -> Result<Vec<Player>, DBusError> {
 pub fn stream_players(retry_delay: u64) -> PlayerStream {
     return PlayerStream::new(retry_delay);
 }
+
+/// Same as [`stream_players`], but looks for players on the given bus.
+pub fn stream_players_on(bus: BusConfig, retry_delay: u64) -> PlayerStream {
+    return PlayerStream::with_bus(retry_delay, bus);
+}
+
+/// Creates a stream of `Result<Player, Error>`. Works like [`stream_players`], but when something
+/// goes wrong the stream yields an [`error::Error`] and keeps running instead of panicking.
+pub fn try_stream_players(retry_delay: u64) -> TryPlayerStream {
+    return TryPlayerStream::new(retry_delay);
+}
+
+/// Same as [`try_stream_players`], but looks for players on the given bus.
+pub fn try_stream_players_on(bus: BusConfig, retry_delay: u64) -> TryPlayerStream {
+    return TryPlayerStream::with_bus(retry_delay, bus);
+}
+
+/// Creates a stream of the events of every player, see [`MergedEventsStream`]. Players are
+/// picked up as they appear, checking every `retry_delay` milliseconds.
+pub fn stream_all_events(retry_delay: u64) -> MergedEventsStream {
+    return MergedEventsStream::new(retry_delay);
+}
+
+/// Same as [`stream_all_events`], but looks for players on the given bus.
+pub fn stream_all_events_on(bus: BusConfig, retry_delay: u64) -> MergedEventsStream {
+    return MergedEventsStream::with_bus(retry_delay, bus);
+}
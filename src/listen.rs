@@ -0,0 +1,266 @@
+//! [`ListenTracker`] works out how long each track was actually listened to, from the
+//! [`ProgressClone`]s of a [`crate::progress::ProgressStream`].
+
+use std::time::{Duration, Instant, SystemTime};
+
+use mpris::{Metadata, PlaybackStatus};
+
+use crate::fake_progress::ProgressClone;
+
+/// How close to its end a track must have stopped to count as completed. Shared with
+/// [`crate::completion`] and [`crate::position`], so they agree on when a track finished.
+pub(crate) const END_TOLERANCE: Duration = Duration::from_secs(5);
+
+/// One track played from start to end, or until something else happened.
+#[derive(Debug, Clone)]
+pub struct Listen {
+    metadata: Metadata,
+    started_at: SystemTime,
+    ended_at: SystemTime,
+    listened: Duration,
+    last_position: Duration,
+}
+
+impl Listen {
+    /// Creates a listen without following a player, for tests.
+    #[cfg(all(test, any(feature = "history", feature = "scrobble")))]
+    pub(crate) fn from_parts(metadata: Metadata, started_at: SystemTime, listened: Duration, last_position: Duration) -> Self {
+        return Listen { metadata, started_at, ended_at: started_at + listened, listened, last_position };
+    }
+
+    /// The metadata of the track, as it was when the listen ended.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// When the track started playing.
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    /// When the track stopped playing, or another track started.
+    pub fn ended_at(&self) -> SystemTime {
+        self.ended_at
+    }
+
+    /// How long the track was playing for. Time spent paused does not count, and neither do
+    /// parts skipped by seeking.
+    pub fn listened(&self) -> Duration {
+        self.listened
+    }
+
+    /// The length of the track, if the player knows it.
+    pub fn length(&self) -> Option<Duration> {
+        self.metadata.length()
+    }
+
+    /// The position the track was at when the listen ended.
+    pub fn last_position(&self) -> Duration {
+        self.last_position
+    }
+
+    /// Whether the track played until the end, as opposed to being skipped or stopped.
+    pub fn completed(&self) -> bool {
+        match self.length() {
+            Some(length) => self.last_position + END_TOLERANCE >= length,
+            None => false,
+        }
+    }
+}
+
+/// What a [`ListenTracker`] noticed in a new progress.
+#[derive(Debug, Clone)]
+pub enum ListenEvent {
+    /// A track started playing.
+    Started(Metadata),
+    /// A track ended, was replaced by another, or playback stopped.
+    Ended(Listen),
+}
+
+/// The listen in progress.
+#[derive(Debug, Clone)]
+struct CurrentListen {
+    key: Option<String>,
+    metadata: Metadata,
+    started_at: SystemTime,
+    listened: Duration,
+    /// When the track last started or resumed playing, while it is playing.
+    playing_since: Option<Instant>,
+    /// The last progress, to work out the position when the listen ends.
+    progress: ProgressClone,
+}
+
+impl CurrentListen {
+    fn finish(mut self, at: Instant) -> Listen {
+        if let Some(since) = self.playing_since.take() {
+            self.listened += at.saturating_duration_since(since);
+        }
+        // The progress may be old, it only changes when the player sends something
+        let mut last_position = self.progress.initial_position();
+        if self.progress.playback_status() == PlaybackStatus::Playing {
+            let elapsed = at.saturating_duration_since(*self.progress.created_at());
+            last_position += elapsed.mul_f64(self.progress.playback_rate().max(0.0));
+        }
+        if let Some(length) = self.metadata.length() {
+            last_position = last_position.min(length);
+        }
+        return Listen {
+            metadata: self.metadata,
+            started_at: self.started_at,
+            ended_at: SystemTime::now(),
+            listened: self.listened,
+            last_position,
+        };
+    }
+}
+
+/// Follows the tracks of one player through its progress and keeps count of the time each was
+/// playing for. Feed it every item of a [`crate::progress::ProgressStream`].
+#[derive(Debug, Clone, Default)]
+pub struct ListenTracker {
+    current: Option<CurrentListen>,
+}
+
+impl ListenTracker {
+    /// Creates a new [`ListenTracker`].
+    pub fn new() -> Self {
+        ListenTracker::default()
+    }
+
+    /// Looks at a new progress of the player.
+    pub fn observe(&mut self, progress: &ProgressClone) -> Vec<ListenEvent> {
+        let mut events = vec![];
+        let at = *progress.created_at();
+        let playing = progress.playback_status() == PlaybackStatus::Playing;
+        // Metadata such as the title may arrive late, only the track id or url tell tracks apart
+        let key = progress.track_key();
+
+        let ended = match &self.current {
+            Some(current) => current.key != key || progress.playback_status() == PlaybackStatus::Stopped,
+            None => false,
+        };
+        if ended {
+            if let Some(current) = self.current.take() {
+                events.push(ListenEvent::Ended(current.finish(at)));
+            }
+        }
+
+        match &mut self.current {
+            Some(current) => {
+                // Wall time while playing is what counts, so a seek does not add or take away
+                match (current.playing_since, playing) {
+                    (Some(since), false) => {
+                        current.listened += at.saturating_duration_since(since);
+                        current.playing_since = None;
+                    },
+                    (None, true) => current.playing_since = Some(at),
+                    _ => {},
+                }
+                current.metadata = progress.metadata().clone();
+                current.progress = progress.clone();
+            },
+            None if progress.playback_status() != PlaybackStatus::Stopped && !progress.metadata().is_empty() => {
+                self.current = Some(CurrentListen {
+                    key,
+                    metadata: progress.metadata().clone(),
+                    started_at: SystemTime::now(),
+                    listened: Duration::ZERO,
+                    playing_since: if playing { Some(at) } else { None },
+                    progress: progress.clone(),
+                });
+                events.push(ListenEvent::Started(progress.metadata().clone()));
+            },
+            None => {},
+        }
+        return events;
+    }
+
+    /// How long the current track has been listened to so far.
+    pub fn listened(&self) -> Option<Duration> {
+        let current = self.current.as_ref()?;
+        let playing = current.playing_since.map(|x| x.elapsed()).unwrap_or_default();
+        return Some(current.listened + playing);
+    }
+
+    /// Ends the current listen, such as when the player quit.
+    pub fn finish(&mut self) -> Option<Listen> {
+        self.current.take().map(|x| x.finish(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mpris::MetadataValue;
+
+    use super::*;
+
+    fn progress(track: u32, status: PlaybackStatus, position: u64, instant: Instant) -> ProgressClone {
+        let mut fields = HashMap::new();
+        fields.insert("mpris:trackid".to_string(), MetadataValue::String(format!("/org/mpris/MediaPlayer2/Track/{}", track)));
+        fields.insert("mpris:length".to_string(), MetadataValue::I64(300_000_000));
+        return ProgressClone::from_parts(Metadata::from(fields), status, Duration::from_secs(position), instant);
+    }
+
+    /// Feeds `steps` of (seconds from the start, track, status, position) and returns the
+    /// listens that ended.
+    fn listens(steps: &[(u64, u32, PlaybackStatus, u64)]) -> Vec<Listen> {
+        let start = Instant::now();
+        let mut tracker = ListenTracker::new();
+        let mut ended = vec![];
+        for (at, track, status, position) in steps {
+            for event in tracker.observe(&progress(*track, *status, *position, start + Duration::from_secs(*at))) {
+                if let ListenEvent::Ended(listen) = event {
+                    ended.push(listen);
+                }
+            }
+        }
+        return ended;
+    }
+
+    #[test]
+    fn counts_time_spent_playing() {
+        let ended = listens(&[
+            (0, 1, PlaybackStatus::Playing, 0),
+            (30, 2, PlaybackStatus::Playing, 0),
+        ]);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].listened(), Duration::from_secs(30));
+        assert_eq!(ended[0].last_position(), Duration::from_secs(30));
+        assert!(!ended[0].completed());
+    }
+
+    #[test]
+    fn pauses_do_not_count() {
+        let ended = listens(&[
+            (0, 1, PlaybackStatus::Playing, 0),
+            (10, 1, PlaybackStatus::Paused, 10),
+            (70, 1, PlaybackStatus::Playing, 10),
+            (80, 1, PlaybackStatus::Stopped, 0),
+        ]);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].listened(), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn seeks_do_not_count() {
+        let ended = listens(&[
+            (0, 1, PlaybackStatus::Playing, 0),
+            (10, 1, PlaybackStatus::Playing, 200),
+            (20, 1, PlaybackStatus::Playing, 5),
+            (30, 2, PlaybackStatus::Playing, 0),
+        ]);
+        assert_eq!(ended[0].listened(), Duration::from_secs(30));
+        assert_eq!(ended[0].last_position(), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn tracks_that_reach_the_end_are_completed() {
+        let ended = listens(&[
+            (0, 1, PlaybackStatus::Playing, 290),
+            (10, 2, PlaybackStatus::Playing, 0),
+        ]);
+        assert!(ended[0].completed());
+    }
+}
@@ -1,9 +1,11 @@
 //! [`PlayerStream`] Handles player connections. It will try for a connection to a new mpris player forever. 
 
-use std::{time::Duration, task::{Waker, Poll}};
+use std::{time::Duration, task::Poll, sync::{Arc, Weak, atomic::{AtomicBool, AtomicUsize, Ordering}}};
 
-use async_std::{task, stream::Stream};
-use mpris::{Player, PlayerFinder};
+use async_std::{task, stream::Stream, channel::{unbounded, Receiver, Sender}};
+use mpris::{DBusError, Player, PlayerFinder, FindingError};
+
+use crate::{connection::BusConfig, error::Error, reconnect::Backoff, waker::WakerSlot};
 
 /// The PlayerStream, which will keep checking for players forever. Created by calling [`crate::stream_players`]
 #[derive(Default, Debug)]
@@ -11,70 +13,118 @@ pub struct PlayerStream {
     players: Vec<Player>,
     index: usize,
     retry_delay: u64,
+    bus: BusConfig,
+    // Shared with the background watcher. Only the stream holds a strong reference, so the
+    // watcher stops once the stream is dropped.
+    watcher: Option<Arc<WatcherState>>,
+    // Set once the connection to DBus is lost, until it has been re-established
+    disconnected: bool,
+    reconnections: Vec<Sender<()>>,
+}
+
+#[derive(Debug, Default)]
+struct WatcherState {
+    waker: WakerSlot,
+    players_len: AtomicUsize,
+    disconnected: AtomicBool,
 }
 
 impl PlayerStream {
     /// Creates a new [`PlayerStream`]. Every `retry_delay` milliseconds it will try for a new
     /// connection.
     pub fn new(retry_delay: u64) -> Self {
-        return PlayerStream { players: vec![], index: 0, retry_delay };
+        return PlayerStream::with_bus(retry_delay, BusConfig::Session);
+    }
+
+    /// Creates a new [`PlayerStream`] that looks for players on the given bus.
+    pub fn with_bus(retry_delay: u64, bus: BusConfig) -> Self {
+        return PlayerStream { players: vec![], index: 0, retry_delay, bus, watcher: None, disconnected: false, reconnections: vec![] };
     }
 
-    async fn wake_after_change(waker: Waker, retry_delay: u64, players_len: usize) {
+    /// Returns a reciever that gets a message every time the stream has reconnected to DBus after
+    /// losing its connection. After reconnecting, every player is yielded again since the old
+    /// [`Player`]s are tied to the dead connection.
+    pub fn get_reconnections(&mut self) -> Receiver<()> {
+        let (s, r) = unbounded();
+        self.reconnections.push(s);
+        return r;
+    }
+
+    async fn wake_after_change(state: Weak<WatcherState>, retry_delay: u64, bus: BusConfig) {
+        let mut backoff = Backoff::default();
+        let mut delay = Duration::from_millis(retry_delay);
         loop {
-            task::sleep(Duration::from_millis(retry_delay)).await;
-            let finder = match PlayerFinder::new() {
-                Ok(x) => x,
-                Err(e) => panic!("Unexpected DBus Error: {}", e),
+            task::sleep(delay).await;
+            delay = Duration::from_millis(retry_delay);
+            let state = match state.upgrade() {
+                Some(x) => x,
+                None => return,
             };
-            let found_players = match finder.find_all() {
+            let disconnected = state.disconnected.load(Ordering::SeqCst);
+            let bus = bus.clone();
+            // Finding players blocks on DBus, keep it off the executor
+            let found = task::spawn_blocking(move || PlayerStream::count_players(&bus, disconnected)).await;
+            let found_len = match found {
                 Ok(x) => x,
-                Err(mpris::FindingError::NoPlayerFound) => continue,
-                //Err(mpris::FindingError::DBusError(x)) => panic!("{}", x),
-                Err(mpris::FindingError::DBusError(x)) => {eprintln!("DbusError: {}", x); continue;},
+                Err(_) => {
+                    delay = delay.max(backoff.next_delay());
+                    continue;
+                },
             };
-            if found_players.len() != players_len {
-                waker.wake_by_ref();
-                return;
+            backoff.reset();
+            if disconnected {
+                state.waker.wake();
+                continue;
+            }
+            match found_len {
+                Some(x) if x != state.players_len.load(Ordering::SeqCst) => state.waker.wake(),
+                _ => {},
             }
         }
     }
-}
 
-impl Stream for PlayerStream {
-    type Item = Player;
+    /// Connects to `bus` and counts the players on it. Fails if the bus can't be reached, and
+    /// gives `None` if the players could not be counted. Nothing is counted when `disconnected`,
+    /// reaching the bus is all that matters then.
+    fn count_players(bus: &BusConfig, disconnected: bool) -> Result<Option<usize>, DBusError> {
+        let finder = bus.finder()?;
+        if disconnected {
+            return Ok(None);
+        }
+        return match finder.find_all() {
+            Ok(x) => Ok(Some(x.len())),
+            Err(FindingError::NoPlayerFound) => Ok(None),
+            Err(FindingError::DBusError(x)) => {eprintln!("DbusError: {}", x); Ok(None)},
+        };
+    }
+}
 
-    fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
-        let finder = match PlayerFinder::new() {
+impl PlayerStream {
+    /// Looks for new players and drops the ones that have quit.
+    fn refresh_players(&mut self, finder: &PlayerFinder) -> Result<(), Error> {
+        let all_players = match finder.find_all() {
             Ok(x) => x,
-            //Err(e) => panic!("DBus Error: {}", e),
-            Err(_) => return Poll::Ready(None),
+            Err(FindingError::NoPlayerFound) => vec![],
+            Err(FindingError::DBusError(e)) => return Err(Error::DBus(e)),
         };
+
         if self.players.len() != 0 {
             let mut decrement_num = 0;
             // Filters out dead connections
-            self.players = self.players.iter().filter(|x| {
+            let players = self.players.iter().filter(|x| {
                 if !x.is_running() {
                     decrement_num += 1;
                 }
                 x.is_running()
             }).map(|x| {
-                match finder.find_by_name(x.identity()) {
-                    Ok(x) => x,
-                    Err(e) => panic!("Unexpected error. Player may have quit after check. {}", e),
-                }
-            }).collect();
-
-            self.index -= decrement_num;
+                finder.find_by_name(x.identity()).map_err(|e| Error::from_finding(e, x.identity()))
+            }).collect::<Result<Vec<Player>, Error>>()?;
+            self.players = players;
 
-            let all_players = match finder.find_all() {
-                Ok(x) => x,
-                //Err(_) => return Poll::Ready(None),
-                Err(e) => panic!("DBus error? {}", e),
-            };
+            self.index = self.index.saturating_sub(decrement_num);
 
             //Kind of goofy work around
-            let new_players = all_players.iter().filter(|x| {
+            let new_players = all_players.into_iter().filter(|x| {
                 let copy_of_players = self.players.iter();
                 for potential_player in copy_of_players {
                     if potential_player.unique_name() == x.unique_name() {
@@ -82,32 +132,149 @@ impl Stream for PlayerStream {
                     }
                 }
                 return true;
-            }).map(|x| {finder.find_by_name(x.identity()).unwrap()}).collect::<Vec<Player>>();
+            }).collect::<Vec<Player>>();
 
             self.players.extend(new_players);
         } else {
-            let all_players = match finder.find_all() {
-                Ok(x) => x,
-                //Err(_) => return Poll::Ready(None),
-                Err(e) => panic!("DBus error? {}", e),
-            };
             self.players.extend(all_players);
         }
+        return Ok(());
+    }
+
+    /// Shared by [`PlayerStream`] and [`TryPlayerStream`]. Never ends, errors are handed to the
+    /// caller to decide what to do with them. A lost connection is only reported once, after
+    /// that the stream waits for the watcher to reconnect.
+    fn poll_player(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<Player, Error>> {
+        let finder = match self.bus.finder() {
+            Ok(x) => x,
+            Err(e) => {
+                let already_disconnected = self.disconnected;
+                self.disconnected = true;
+                self.watch(cx);
+                if already_disconnected {
+                    return Poll::Pending;
+                }
+                return Poll::Ready(Err(Error::Connection(e)));
+            },
+        };
+        if self.disconnected {
+            // Players from the old connection are dead, start over and yield all of them again
+            self.disconnected = false;
+            self.players.clear();
+            self.index = 0;
+            if let Some(state) = &self.watcher {
+                state.disconnected.store(false, Ordering::SeqCst);
+            }
+            self.reconnections.retain(|x| x.try_send(()).is_ok());
+        }
+        if let Err(e) = self.refresh_players(&finder) {
+            return Poll::Ready(Err(e));
+        }
         
         // Basically manually making an iterator
         if self.index < self.players.len() {
-            let new_player: Player = finder.find_by_name(self.players.get(self.index).unwrap().identity()).unwrap();
+            let identity = self.players.get(self.index).unwrap().identity().to_string();
+            let new_player = match finder.find_by_name(&identity) {
+                Ok(x) => x,
+                Err(e) => return Poll::Ready(Err(Error::from_finding(e, &identity))),
+            };
             self.index += 1;
-            return Poll::Ready(Some(new_player));
+            return Poll::Ready(Ok(new_player));
         } else {
-            let waker = cx.waker().to_owned();
-            let retry_delay = self.retry_delay;
-            let players_len = self.players.len();
+            self.watch(cx);
+            return Poll::Pending;
+        }
+    }
+
+    /// Makes sure the background watcher is running and will wake the current task. Only one
+    /// watcher is ever spawned per stream, later polls just update its state.
+    fn watch(&mut self, cx: &mut task::Context<'_>) {
+        let players_len = self.players.len();
+        let retry_delay = self.retry_delay;
+        let disconnected = self.disconnected;
+        let bus = self.bus.clone();
+        let state = self.watcher.get_or_insert_with(|| {
+            let state = Arc::new(WatcherState::default());
+            let weak = Arc::downgrade(&state);
             task::spawn(async move {
-                PlayerStream::wake_after_change(waker, retry_delay, players_len).await;
+                PlayerStream::wake_after_change(weak, retry_delay, bus).await;
             });
-            return Poll::Pending;
+            state
+        });
+        state.players_len.store(players_len, Ordering::SeqCst);
+        state.disconnected.store(disconnected, Ordering::SeqCst);
+        state.waker.register(cx.waker());
+    }
+}
+
+impl Stream for PlayerStream {
+    type Item = Player;
+
+    fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
+        match self.poll_player(cx) {
+            Poll::Ready(Ok(player)) => Poll::Ready(Some(player)),
+            // The watcher is already registered and wakes us once DBus is back
+            Poll::Ready(Err(Error::Connection(_))) => Poll::Pending,
+            Poll::Ready(Err(e)) => panic!("Unexpected error. Player may have quit after check. {}", e),
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
+/// Like [`PlayerStream`], but yields an [`Error`] instead of panicking when something goes wrong.
+/// The stream keeps running after an error, polling it again will retry. Created by calling
+/// [`crate::try_stream_players`]
+#[derive(Default, Debug)]
+pub struct TryPlayerStream {
+    inner: PlayerStream,
+}
+
+impl TryPlayerStream {
+    /// Creates a new [`TryPlayerStream`]. Every `retry_delay` milliseconds it will try for a new
+    /// connection.
+    pub fn new(retry_delay: u64) -> Self {
+        return TryPlayerStream { inner: PlayerStream::new(retry_delay) };
+    }
+
+    /// Creates a new [`TryPlayerStream`] that looks for players on the given bus.
+    pub fn with_bus(retry_delay: u64, bus: BusConfig) -> Self {
+        return TryPlayerStream { inner: PlayerStream::with_bus(retry_delay, bus) };
+    }
+
+    /// See [`PlayerStream::get_reconnections`].
+    pub fn get_reconnections(&mut self) -> Receiver<()> {
+        self.inner.get_reconnections()
+    }
+}
+
+impl Stream for TryPlayerStream {
+    type Item = Result<Player, Error>;
+
+    fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
+        self.inner.poll_player(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::{future, stream::StreamExt};
+
+    use super::*;
+
+    #[test]
+    fn polling_spawns_one_watcher() {
+        // Nothing listens there, so every poll ends up registering with the watcher
+        let bus = BusConfig::Address("unix:path=/nonexistent/mpris-async-test".to_string());
+        let mut stream = PlayerStream::with_bus(60_000, bus);
+        task::block_on(async {
+            for _ in 0..100 {
+                let next = future::timeout(Duration::from_millis(1), stream.next()).await;
+                assert!(next.is_err());
+            }
+        });
+        let state = stream.watcher.as_ref().unwrap();
+        assert_eq!(Arc::strong_count(state), 1);
+        // Each watcher holds one weak reference
+        assert_eq!(Arc::weak_count(state), 1);
+    }
+}
@@ -0,0 +1,266 @@
+//! Async access to the [`org.mpris.MediaPlayer2.Playlists`][playlists] interface, which the mpris
+//! crate does not cover. [`PlaylistEventsStream`] tracks changes to the playlists.
+//!
+//! [playlists]: https://specifications.freedesktop.org/mpris-spec/latest/Playlists_Interface.html
+
+use std::{collections::HashSet, str::FromStr, thread};
+
+use async_std::{channel::Receiver, task, stream::Stream};
+use dbus::{arg::{prop_cast, RefArg}, ffidisp::stdintf::org_freedesktop_dbus::Properties, Path};
+
+use crate::{error::Error, handle::PlayerHandle, signals::{Signal, SignalListener}, waker::{waking_channel, WakingReceiver, WakingSender}};
+
+pub(crate) const PLAYLISTS_INTERFACE: &str = "org.mpris.MediaPlayer2.Playlists";
+
+/// How many playlists [`Playlists::get_all_playlists`] asks for at a time.
+const PAGE_SIZE: u32 = 100;
+
+type RawPlaylist = (Path<'static>, String, String);
+
+/// A playlist of the player.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Playlist {
+    id: String,
+    name: String,
+    icon: String,
+}
+
+impl Playlist {
+    /// The object path that identifies the playlist.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The name of the playlist, meant to be shown to the user.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// URI of an icon for the playlist, if it has one.
+    pub fn icon(&self) -> Option<&str> {
+        if self.icon.is_empty() {
+            return None;
+        }
+        Some(&self.icon)
+    }
+
+    fn from_raw(raw: RawPlaylist) -> Self {
+        Playlist { id: raw.0.to_string(), name: raw.1, icon: raw.2 }
+    }
+}
+
+/// The order playlists can be listed in.
+///
+/// See: [MPRIS2 specification about `Playlist_Ordering`][ordering]
+///
+/// [ordering]: https://specifications.freedesktop.org/mpris-spec/latest/Playlists_Interface.html#Enum:Playlist_Ordering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistOrdering {
+    /// Alphabetical ordering by name, ascending.
+    Alphabetical,
+    /// Ordering by creation date, oldest first.
+    CreationDate,
+    /// Ordering by last modified date, oldest first.
+    ModifiedDate,
+    /// Ordering by date of last playback, oldest first.
+    LastPlayDate,
+    /// A user-defined ordering.
+    UserDefined,
+}
+
+impl PlaylistOrdering {
+    /// The value used for this ordering on DBus.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlaylistOrdering::Alphabetical => "Alphabetical",
+            PlaylistOrdering::CreationDate => "Created",
+            PlaylistOrdering::ModifiedDate => "Modified",
+            PlaylistOrdering::LastPlayDate => "Played",
+            PlaylistOrdering::UserDefined => "User",
+        }
+    }
+}
+
+impl FromStr for PlaylistOrdering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Alphabetical" => Ok(PlaylistOrdering::Alphabetical),
+            "Created" => Ok(PlaylistOrdering::CreationDate),
+            "Modified" => Ok(PlaylistOrdering::ModifiedDate),
+            "Played" => Ok(PlaylistOrdering::LastPlayDate),
+            "User" => Ok(PlaylistOrdering::UserDefined),
+            other => Err(format!("Unknown playlist ordering {}", other)),
+        }
+    }
+}
+
+/// Async access to the playlists of a player. Created with [`PlayerHandle::playlists`].
+#[derive(Debug, Clone)]
+pub struct Playlists {
+    handle: PlayerHandle,
+}
+
+impl PlayerHandle {
+    /// Access to the playlists of the player. Not every player supports playlists, calls will
+    /// return an error for those that don't.
+    pub fn playlists(&self) -> Playlists {
+        Playlists { handle: self.clone() }
+    }
+}
+
+impl Playlists {
+    /// Returns up to `max_count` playlists, starting at `index`, sorted by `order`.
+    pub async fn get_playlists(&self, index: u32, max_count: u32, order: PlaylistOrdering, reverse_order: bool) -> Result<Vec<Playlist>, Error> {
+        self.handle.run_raw(move |path| {
+            let (playlists,): (Vec<RawPlaylist>,) = path.method_call(PLAYLISTS_INTERFACE, "GetPlaylists", (index, max_count, order.as_str(), reverse_order))?;
+            Ok(playlists.into_iter().map(Playlist::from_raw).collect())
+        }).await
+    }
+
+    /// Returns every playlist sorted by `order`, fetching them a page at a time. Stops at a page
+    /// without any new playlists, in case the player ignores `index` and sends the same page
+    /// again.
+    pub async fn get_all_playlists(&self, order: PlaylistOrdering, reverse_order: bool) -> Result<Vec<Playlist>, Error> {
+        let mut playlists = vec![];
+        let mut seen = HashSet::new();
+        loop {
+            let page = self.get_playlists(playlists.len() as u32, PAGE_SIZE, order, reverse_order).await?;
+            let done = (page.len() as u32) < PAGE_SIZE;
+            let before = playlists.len();
+            playlists.extend(page.into_iter().filter(|x| seen.insert(x.id.clone())));
+            if done || playlists.len() == before {
+                return Ok(playlists);
+            }
+        }
+    }
+
+    /// The number of playlists.
+    pub async fn count(&self) -> Result<u32, Error> {
+        self.handle.run_raw(|path| path.get(PLAYLISTS_INTERFACE, "PlaylistCount")).await
+    }
+
+    /// The orderings the player supports. Orderings this crate does not know about are left out.
+    pub async fn orderings(&self) -> Result<Vec<PlaylistOrdering>, Error> {
+        self.handle.run_raw(|path| {
+            let orderings: Vec<String> = path.get(PLAYLISTS_INTERFACE, "Orderings")?;
+            Ok(orderings.iter().filter_map(|x| x.parse().ok()).collect())
+        }).await
+    }
+
+    /// The currently active playlist, if there is one.
+    pub async fn active_playlist(&self) -> Result<Option<Playlist>, Error> {
+        self.handle.run_raw(|path| {
+            let (valid, playlist): (bool, RawPlaylist) = path.get(PLAYLISTS_INTERFACE, "ActivePlaylist")?;
+            Ok(if valid { Some(Playlist::from_raw(playlist)) } else { None })
+        }).await
+    }
+
+    /// Starts playing the given playlist.
+    pub async fn activate_playlist(&self, playlist: &Playlist) -> Result<(), Error> {
+        let id = playlist.id.clone();
+        self.handle.run_raw(move |path| {
+            let id = Path::new(id).map_err(|e| dbus::Error::new_failed(&e))?;
+            path.method_call(PLAYLISTS_INTERFACE, "ActivatePlaylist", (id,))
+        }).await
+    }
+
+    /// Creates a new [`PlaylistEventsStream`] for the player.
+    pub fn events(&self) -> PlaylistEventsStream {
+        PlaylistEventsStream::new(&self.handle)
+    }
+}
+
+/// A change to the playlists of a player.
+#[derive(Debug, Clone)]
+pub enum PlaylistEvent {
+    /// The name or icon of a playlist changed.
+    PlaylistChanged(Playlist),
+    /// Playlists were added or removed. The new count is provided.
+    CountChanged(u32),
+    /// Another playlist became active, or no playlist is active anymore.
+    ActivePlaylistChanged(Option<Playlist>),
+}
+
+/// Infinite stream of [`PlaylistEvent`]s. Makes a new thread to listen for the `PlaylistChanged`
+/// signal and property changes. The thread only closes once the player has quit.
+#[derive(Debug, Clone)]
+pub struct PlaylistEventsStream {
+    reciever: WakingReceiver<PlaylistEvent>,
+}
+
+impl PlaylistEventsStream {
+    /// Creates a new [`PlaylistEventsStream`]. Streams made from cloning share the same thread.
+    pub fn new(handle: &PlayerHandle) -> Self {
+        let (sender, reciever) = waking_channel();
+        let handle = handle.clone();
+        thread::spawn(move || PlaylistEventsStream::playlist_listener(handle, sender));
+        return PlaylistEventsStream { reciever };
+    }
+
+    fn playlist_listener(handle: PlayerHandle, sender: WakingSender<PlaylistEvent>) {
+        let signals = match SignalListener::new(&handle) {
+            Ok(x) => x,
+            Err(_) => {
+                sender.close();
+                return;
+            },
+        };
+        for signal in signals {
+            let events = match signal {
+                Signal::PropertiesChanged { interface, changed, .. } if interface == PLAYLISTS_INTERFACE => {
+                    let mut events = vec![];
+                    if let Some(count) = prop_cast::<u32>(&changed, "PlaylistCount") {
+                        events.push(PlaylistEvent::CountChanged(*count));
+                    }
+                    if let Some(active) = changed.get("ActivePlaylist") {
+                        events.push(PlaylistEvent::ActivePlaylistChanged(parse_active_playlist(&*active.0)));
+                    }
+                    events
+                },
+                Signal::Other(message) if message.interface().as_deref() == Some(PLAYLISTS_INTERFACE) && message.member().as_deref() == Some("PlaylistChanged") => {
+                    match message.read1::<RawPlaylist>() {
+                        Ok(x) => vec![PlaylistEvent::PlaylistChanged(Playlist::from_raw(x))],
+                        Err(_) => continue,
+                    }
+                },
+                Signal::PlayerQuit => break,
+                _ => continue,
+            };
+            for event in events {
+                if !sender.send(event) {
+                    return;
+                }
+            }
+        }
+        sender.close();
+    }
+
+    /// Access to the reciever used to send events around.
+    pub fn get_reciever(&self) -> Receiver<PlaylistEvent> {
+        self.reciever.reciever()
+    }
+}
+
+/// Reads the `(b(oss))` value of `ActivePlaylist` out of a property change.
+fn parse_active_playlist(value: &dyn RefArg) -> Option<Playlist> {
+    let mut fields = value.as_iter()?;
+    let valid = fields.next()?.as_u64()? != 0;
+    if !valid {
+        return None;
+    }
+    let mut playlist = fields.next()?.as_iter()?;
+    let id = playlist.next()?.as_str()?.to_string();
+    let name = playlist.next()?.as_str()?.to_string();
+    let icon = playlist.next()?.as_str()?.to_string();
+    return Some(Playlist { id, name, icon });
+}
+
+impl Stream for PlaylistEventsStream {
+    type Item = PlaylistEvent;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
+        self.reciever.poll_recv(cx)
+    }
+}
@@ -0,0 +1,195 @@
+//! Futures that resolve at a point in the playback of a track, see [`PlayerHandle::at_position`]
+//! and [`PlayerHandle::track_ended`].
+
+use std::{future::Future, pin::Pin, task::{Context, Poll}, thread, time::Duration};
+
+use mpris::{DBusError, PlaybackStatus, Player};
+
+use crate::{error::Error, fake_progress::ProgressClone, handle::PlayerHandle, listen::END_TOLERANCE, signals::{Signal, SignalListener}, waker::{waking_channel, WakingReceiver, WakingSender}};
+
+/// Longest time to wait without checking whether the future was dropped.
+const MAX_WAIT_MS: u32 = 1000;
+
+/// How close to the target counts as having reached it. Avoids waking up over and over for the
+/// last few milliseconds.
+const POSITION_TOLERANCE: Duration = Duration::from_millis(5);
+
+/// How a [`PlayerHandle::at_position`] future resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PositionOutcome {
+    /// The position was reached, or seeked past.
+    Reached,
+    /// Another track started before the position was reached.
+    TrackChanged,
+}
+
+/// How a [`PlayerHandle::track_ended`] future resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackEnd {
+    /// The track played until its end.
+    Finished,
+    /// Another track started before the end was reached.
+    Skipped,
+    /// Playback was stopped.
+    Stopped,
+}
+
+/// A future computed from the position of a player. Created by [`PlayerHandle::at_position`]
+/// and [`PlayerHandle::track_ended`]. A thread sleeps until the expected time and is woken
+/// early by the player's signals, so it reacts to seeking, rate changes, pausing and track
+/// changes without polling. Dropping the future stops the thread within a second.
+#[derive(Debug)]
+pub struct PlaybackFuture<T> {
+    reciever: WakingReceiver<Result<T, Error>>,
+}
+
+impl<T: Send + 'static> PlaybackFuture<T> {
+    fn spawn<F>(handle: &PlayerHandle, check: F) -> Self
+    where
+        F: FnMut(&ProgressClone, &Option<String>) -> Check<T> + Send + 'static,
+    {
+        let (sender, reciever) = waking_channel();
+        let handle = handle.clone();
+        thread::spawn(move || {
+            let result = wait_for(&handle, check, &sender);
+            if let Some(result) = result {
+                sender.send(result);
+            }
+            sender.close();
+        });
+        return PlaybackFuture { reciever };
+    }
+}
+
+impl<T> Future for PlaybackFuture<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.reciever.poll_recv(cx) {
+            Poll::Ready(Some(x)) => Poll::Ready(x),
+            Poll::Ready(None) => Poll::Ready(Err(lost_connection())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// What to do after looking at the progress of the player.
+enum Check<T> {
+    Done(T),
+    /// Check again after this long, or sooner if the player changes.
+    WaitFor(Duration),
+    /// Only check again once the player changes.
+    WaitForChange,
+}
+
+impl PlayerHandle {
+    /// Resolves once the current track has reached `position`. Resolves right away if it already
+    /// has, and with [`PositionOutcome::TrackChanged`] if another track starts first.
+    pub fn at_position(&self, position: Duration) -> PlaybackFuture<PositionOutcome> {
+        PlaybackFuture::spawn(self, move |progress, track| {
+            if progress.track_key() != *track {
+                return Check::Done(PositionOutcome::TrackChanged);
+            }
+            return match until(progress, position) {
+                Some(wait) => wait,
+                None => Check::Done(PositionOutcome::Reached),
+            };
+        })
+    }
+
+    /// Resolves once the current track has ended, been skipped or playback has stopped.
+    pub fn track_ended(&self) -> PlaybackFuture<TrackEnd> {
+        let mut near_end = false;
+        PlaybackFuture::spawn(self, move |progress, track| {
+            if progress.track_key() != *track {
+                // Players often move on just before the end is expected, that still counts
+                return Check::Done(if near_end { TrackEnd::Finished } else { TrackEnd::Skipped });
+            }
+            near_end = progress.time_left().map_or(false, |x| x <= END_TOLERANCE);
+            if progress.playback_status() == PlaybackStatus::Stopped {
+                return Check::Done(TrackEnd::Stopped);
+            }
+            let length = match progress.length() {
+                Some(x) => x,
+                None => return Check::WaitForChange,
+            };
+            return match until(progress, length) {
+                Some(wait) => wait,
+                None => Check::Done(TrackEnd::Finished),
+            };
+        })
+    }
+}
+
+/// How long until `progress` reaches `position`, or [`None`] if it already has.
+fn until<T>(progress: &ProgressClone, position: Duration) -> Option<Check<T>> {
+    if progress.position() + POSITION_TOLERANCE >= position {
+        return None;
+    }
+    if progress.playback_status() != PlaybackStatus::Playing {
+        return Some(Check::WaitForChange);
+    }
+    return match progress.time_until(position) {
+        Some(x) => Some(Check::WaitFor(x)),
+        None => Some(Check::WaitForChange),
+    };
+}
+
+fn wait_for<T, F>(handle: &PlayerHandle, mut check: F, sender: &WakingSender<Result<T, Error>>) -> Option<Result<T, Error>>
+where
+    F: FnMut(&ProgressClone, &Option<String>) -> Check<T>,
+{
+    // Subscribe first, so no change between reading the progress and waiting is missed
+    let mut signals = match SignalListener::new(handle) {
+        Ok(x) => x,
+        Err(e) => return Some(Err(e)),
+    };
+    let player = match handle.find() {
+        Ok(x) => x,
+        Err(e) => return Some(Err(e)),
+    };
+    let progress = match read(&player) {
+        Ok(x) => x,
+        Err(e) => return Some(Err(e)),
+    };
+    let track = progress.track_key();
+    let mut progress = Some(progress);
+
+    loop {
+        // Every change is followed by a fresh read of the progress anchor
+        let current = match progress.take() {
+            Some(x) => x,
+            None => match read(&player) {
+                Ok(x) => x,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        let (timeout, reread_on_timeout) = match check(&current, &track) {
+            Check::Done(x) => return Some(Ok(x)),
+            Check::WaitFor(x) => (x.as_millis().clamp(1, MAX_WAIT_MS as u128) as u32, true),
+            Check::WaitForChange => (MAX_WAIT_MS, false),
+        };
+
+        loop {
+            if sender.is_closed() {
+                return None;
+            }
+            match signals.next_within(timeout) {
+                Some(Signal::PlayerQuit) => return Some(Err(Error::PlayerQuit(handle.identity().to_string()))),
+                // Seeked, PlaybackStatus, Rate and Metadata all move the anchor
+                Some(Signal::PropertiesChanged { .. }) | Some(Signal::Other(_)) => break,
+                None if signals.is_closed() => return Some(Err(lost_connection())),
+                None if reread_on_timeout => break,
+                None => continue,
+            }
+        }
+    }
+}
+
+fn lost_connection() -> Error {
+    Error::Connection(DBusError::Miscellaneous("Lost connection to DBus".to_string()))
+}
+
+fn read(player: &Player) -> Result<ProgressClone, Error> {
+    ProgressClone::read(player).map_err(Error::from)
+}
@@ -1,26 +1,42 @@
 //! [`ProgressStream`] handles when changes to progress are sent.
 
-use std::{task::{Waker, Poll}, thread};
+use std::{thread, time::{Duration, Instant}};
 
-use async_std::{channel::{unbounded, Sender, Receiver}, stream::Stream};
-use mpris::{Player, PlayerFinder};
+use async_std::{channel::Receiver, stream::Stream};
+use mpris::{FindingError, PlaybackStatus, Player, ProgressTracker};
 
-use crate::fake_progress::ProgressClone;
+use crate::{connection::BusConfig, fake_progress::{DurationExtensions, ProgressClone}, handle::{PlayerHandle, PLAYER_INTERFACE}, quirks::quirks_for, reconnect::{Backoff, PLAYER_LOOKUP_ATTEMPTS}, sanity::{PositionReliability, PositionSanity}, signals::{Signal, SignalListener}, waker::{waking_channel, WakingReceiver, WakingSender}};
+
+/// How often the position is read while it is not known whether it can be trusted.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the position is read after that, in case the player changes its mind.
+const SETTLED_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the connection to DBus is checked, in case it was lost.
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 
 /// Streams changes from [`ProgressTracker`](mpris::ProgressTracker). Makes a new thread to track changes from the player.
-/// This class will only send progress when it has changed since the last check.
+/// This class will only send progress when it has changed since the last check. If the connection
+/// to DBus is lost, the thread finds the player again and sends its progress once reconnected.
+///
+/// While playing, the position is sampled now and then to check it with [`PositionSanity`]. The
+/// verdict is in [`ProgressClone::position_reliability`], and progress is also sent when it
+/// changes.
 #[derive(Debug, Clone)]
 pub struct ProgressStream {
+    reciever: WakingReceiver<ProgressClone>,
+}
+
+/// The thread behind a [`ProgressStream`].
+struct ProgressListener {
     // Cannot share Player, Progress Tick, and TrackList across thread/tasks
     identity: String,
-    progress_channel: (Sender<MaybeProgress>, Receiver<MaybeProgress>),
-    waker: (Sender<Waker>, Receiver<Waker>),
+    sender: WakingSender<ProgressClone>,
     interval: u32,
-}
-
-enum MaybeProgress {
-    ProgressFake(ProgressClone), Stopped,
+    bus: BusConfig,
+    position_fallback: bool,
 }
 
 impl ProgressStream {
@@ -28,63 +44,157 @@ impl ProgressStream {
     /// made from cloning will use the same thread to track changes. The thread only closes when
     /// the player has quit.
     pub fn new(player: &Player, interval: u32) -> Self {
-       let waker = unbounded();
-       let progress_channel = unbounded();
-       let streamer = ProgressStream {identity: player.identity().to_string(), progress_channel, waker, interval };
-       let stream_clone = streamer.clone();
-       thread::spawn(|| stream_clone.progress_listener());
+        return ProgressStream::with_bus(player, interval, BusConfig::Session);
+    }
+
+    /// Creates a new [`ProgressStream`] for a player on the given bus. `bus` must be the bus that
+    /// `player` was found on.
+    pub fn with_bus(player: &Player, interval: u32, bus: BusConfig) -> Self {
+        return ProgressStream::spawn(player, interval, bus, false);
+    }
+
+    /// Creates a new [`ProgressStream`] that replaces the position of a player found to be
+    /// [`PositionReliability::Unreliable`] with one kept by the local clock, anchored on track
+    /// changes, `Seeked` signals and changes to the playback status.
+    pub fn with_position_fallback(player: &Player, interval: u32, bus: BusConfig) -> Self {
+        return ProgressStream::spawn(player, interval, bus, true);
+    }
 
-       return streamer;
+    fn spawn(player: &Player, interval: u32, bus: BusConfig, position_fallback: bool) -> Self {
+        let (sender, reciever) = waking_channel();
+        let listener = ProgressListener { identity: player.identity().to_string(), sender, interval, bus, position_fallback };
+        thread::spawn(move || listener.progress_listener());
+
+        return ProgressStream { reciever };
+    }
+
+    /// Access to the reciever used to send progress around.
+    pub fn get_reciever(&self) -> Receiver<ProgressClone> {
+        self.reciever.reciever()
     }
+}
 
+impl ProgressListener {
+    /// Follows the player until it quits or every stream is dropped, then ends the streams.
     fn progress_listener(self) {
-        let finder = match PlayerFinder::new() {
-            Ok(x) => x,
-            Err(_) => {
-                return;
-            },
-        };
-        let player = match finder.find_by_name(&self.identity) {
-            Ok(x) => x,
-            Err(_) => {
-                match self.progress_channel.0.try_send(MaybeProgress::Stopped) {
-                    Ok(_) => {},
-                    Err(_) => return,
-                };
+        self.follow_player();
+        self.sender.close();
+    }
+
+    fn follow_player(&self) {
+        let mut backoff = Backoff::default();
+        let mut reconnecting = false;
+        loop {
+            let finder = match self.bus.finder() {
+                Ok(x) => x,
+                Err(_) if reconnecting => {
+                    thread::sleep(backoff.next_delay());
+                    continue;
+                },
+                Err(_) => return,
+            };
+            let player = match finder.find_by_name(&self.identity) {
+                Ok(x) => x,
+                Err(FindingError::NoPlayerFound) if reconnecting && backoff.attempts() < PLAYER_LOOKUP_ATTEMPTS => {
+                    thread::sleep(backoff.next_delay());
+                    continue;
+                },
+                Err(FindingError::DBusError(_)) if reconnecting => {
+                    thread::sleep(backoff.next_delay());
+                    continue;
+                },
+                Err(_) => return,
+            };
+            let mut progress_tracker = match player.track_progress(self.interval) {
+                Ok(x) => x,
+                Err(_) if reconnecting => {
+                    thread::sleep(backoff.next_delay());
+                    continue;
+                },
+                Err(_) => return,
+            };
+            if reconnecting {
+                backoff.reset();
+            }
+
+            if !self.follow_progress(&player, &mut progress_tracker, reconnecting) {
                 return;
-            },
-        };
-        let test = player.track_progress(self.interval);
-        let mut progress_tracker = match test {
-            Ok(x) => x,
-            Err(e) => panic!("{}", e),
+            }
+            // The connection was lost, find the player again after a while
+            reconnecting = true;
+            thread::sleep(backoff.next_delay());
+        }
+    }
+
+    /// Sends progress until the player quits or the stream is dropped, then returns false.
+    /// Returns true if the connection to DBus seems to be lost. `reconnected` sends the
+    /// progress right away, since changes made while disconnected were missed.
+    fn follow_progress(&self, player: &Player, progress_tracker: &mut ProgressTracker<'_>, reconnected: bool) -> bool {
+        let quirks = quirks_for(player);
+        let mut sanity = PositionSanity::new(self.position_fallback);
+        if quirks.unreliable_position {
+            sanity.assume_unreliable();
+        }
+        let mut last_sample = Instant::now();
+        let mut last_check = Instant::now();
+        let mut resend = reconnected;
+        // Seeks only matter for the fallback, so only listen for them when it is enabled
+        let mut seeks = match self.position_fallback {
+            true => SignalListener::new(&PlayerHandle::with_bus(player, self.bus.clone())).ok(),
+            false => None,
         };
         loop {
             let tick = progress_tracker.tick();
             if tick.player_quit {
-                match self.progress_channel.0.try_send(MaybeProgress::Stopped) {
-                    Ok(x) => x,
-                    Err(_) => return,
-                };
-
-                return;
+                return false;
+            }
+            let mut progress = ProgressClone::from(tick.progress);
+            quirks.normalize_progress(&mut progress);
+            let progress_changed = tick.progress_changed;
+
+            // A dead connection never reports the player quitting, so check on it now and then
+            if last_check.elapsed() >= CONNECTION_CHECK_INTERVAL {
+                if !player.is_running() {
+                    return true;
+                }
+                last_check = Instant::now();
             }
-            if tick.progress_changed {
-                loop {
-                    match self.waker.1.try_recv() {
-                        Ok(waker) => {
-                            match self.progress_channel.0.try_send(MaybeProgress::ProgressFake(ProgressClone::from(tick.progress))) {
-                                Ok(_) => {},
-                                Err(_) => return,
-                            };
-                            waker.wake_by_ref();
+
+            if let Some(signals) = seeks.as_mut() {
+                while let Some(signal) = signals.next_within(0) {
+                    if let Signal::Other(message) = signal {
+                        if message.interface().as_deref() == Some(PLAYER_INTERFACE) && message.member().as_deref() == Some("Seeked") {
+                            if let Ok(position) = message.read1::<i64>() {
+                                sanity.seeked(Duration::from_micros_ext(position.max(0) as u64));
+                            }
                         }
-                        _ => break,
                     }
                 }
             }
 
-            
+            // Without Seeked, sampling is the only way to notice a seek
+            let sample_interval = match (quirks.missing_seeked, sanity.reliability()) {
+                (true, _) | (_, PositionReliability::Unknown) => SAMPLE_INTERVAL,
+                _ => SETTLED_SAMPLE_INTERVAL,
+            };
+            if progress.playback_status() == PlaybackStatus::Playing && last_sample.elapsed() >= sample_interval {
+                if let Ok(position) = player.get_position() {
+                    progress.position = position;
+                    progress.instant = Instant::now();
+                }
+                last_sample = Instant::now();
+            }
+            let reliability = sanity.reliability();
+            sanity.observe(&mut progress);
+
+            if progress_changed || resend || sanity.reliability() != reliability {
+                if !self.sender.send(progress) {
+                    return false;
+                }
+                resend = false;
+            } else if self.sender.is_closed() {
+                return false;
+            }
         }
     }
 }
@@ -93,22 +203,6 @@ impl Stream for ProgressStream {
     type Item = ProgressClone;
 
     fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
-        match self.waker.0.try_send(cx.waker().clone()) {
-            Ok(_) => {},
-            Err(_) => return Poll::Ready(None),
-        };
-
-        let progress = match self.progress_channel.1.try_recv() {
-            Ok(x) => x,
-            Err(_) => return Poll::Pending,
-        };
-
-
-        match progress {
-            MaybeProgress::ProgressFake(x) => return Poll::Ready(Some(x)),
-            MaybeProgress::Stopped => return Poll::Ready(None),
-        }
-
-
+        self.reciever.poll_recv(cx)
     }
 }
@@ -0,0 +1,265 @@
+//! Known misbehaviour of specific players. [`Quirks`] are looked up by identity or desktop entry
+//! when a stream starts or a control method runs, and are used to normalize what the player
+//! sends and what is sent to it. The built in table can be overridden with [`set_quirks`], or with [`load_quirks`]
+//! from a TOML file when the `quirks-config` feature is enabled.
+
+use std::{collections::HashMap, sync::{OnceLock, RwLock}, time::Duration};
+#[cfg(feature = "quirks-config")]
+use std::{fmt, fs, io, path::Path};
+
+use mpris::{Event, Metadata, Player};
+#[cfg(feature = "quirks-config")]
+use serde::{Deserialize, Deserializer};
+
+use crate::{error::Error, fake_progress::ProgressClone, handle::PlayerHandle};
+
+/// The track id a player sends when it has no track, see the [MPRIS2 specification][no_track].
+///
+/// [no_track]: https://specifications.freedesktop.org/mpris-spec/latest/Player_Interface.html#Property:Metadata
+pub const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// How a player deviates from the MPRIS specification. The default is a player that follows it.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "quirks-config", derive(Deserialize), serde(default, deny_unknown_fields))]
+pub struct Quirks {
+    /// The `Position` property can't be trusted. The position is treated as
+    /// [`Unreliable`](crate::sanity::PositionReliability::Unreliable) from the start, instead of
+    /// after a few bad samples.
+    pub unreliable_position: bool,
+    /// The player does not send `Seeked`, so the position is read more often to notice seeks.
+    pub missing_seeked: bool,
+    /// The volume the player uses for full volume, such as 100.0 for players with a 0-100 range.
+    /// `None` for the 0.0-1.0 range of the specification. Volumes are converted to 0.0-1.0 in
+    /// events, progress and the volume methods of [`PlayerHandle`].
+    pub volume_scale: Option<f64>,
+    /// The player sends the metadata of a new track in several updates within this time. Track
+    /// changes are held back for this long and then sent once with the complete metadata. Set
+    /// as `metadata_settle_ms` in a quirks file.
+    #[cfg_attr(feature = "quirks-config", serde(rename = "metadata_settle_ms", deserialize_with = "millis"))]
+    pub metadata_settle: Option<Duration>,
+    /// The player sends [`NO_TRACK`] as the track id of actual tracks. The track id is removed
+    /// from the metadata, so that the url is used to tell tracks apart.
+    pub placeholder_track_id: bool,
+}
+
+impl Quirks {
+    /// Converts a volume from the player to the 0.0-1.0 range.
+    pub fn normalize_volume(&self, volume: f64) -> f64 {
+        volume / self.volume_scale.unwrap_or(1.0)
+    }
+
+    /// Converts a volume in the 0.0-1.0 range to what the player expects.
+    pub fn denormalize_volume(&self, volume: f64) -> f64 {
+        volume * self.volume_scale.unwrap_or(1.0)
+    }
+
+    /// Applies the quirks to metadata sent by the player.
+    pub fn normalize_metadata(&self, metadata: Metadata) -> Metadata {
+        if !self.placeholder_track_id || metadata.track_id().map_or(true, |x| x.as_str() != NO_TRACK) {
+            return metadata;
+        }
+        let fields: HashMap<_, _> = metadata.into_iter().filter(|(key, _)| key != "mpris:trackid").collect();
+        return Metadata::from(fields);
+    }
+
+    /// Applies the quirks to an event sent by the player.
+    pub fn normalize_event(&self, event: Event) -> Event {
+        match event {
+            Event::VolumeChanged(volume) => Event::VolumeChanged(self.normalize_volume(volume)),
+            Event::TrackChanged(metadata) => Event::TrackChanged(self.normalize_metadata(metadata)),
+            event => event,
+        }
+    }
+
+    /// Applies the quirks to progress read from the player.
+    pub(crate) fn normalize_progress(&self, progress: &mut ProgressClone) {
+        progress.current_volume = self.normalize_volume(progress.current_volume);
+        progress.metadata = self.normalize_metadata(std::mem::take(&mut progress.metadata));
+    }
+}
+
+/// Quirks of players known to misbehave, keyed by lower case identity or desktop entry.
+fn builtin(key: &str) -> Option<Quirks> {
+    match key {
+        // Position is stuck at 0, seeks are not announced and metadata trickles in
+        "spotify" => Some(Quirks {
+            unreliable_position: true,
+            missing_seeked: true,
+            metadata_settle: Some(Duration::from_millis(300)),
+            ..Quirks::default()
+        }),
+        // Browsers send the title first and the rest once the page has loaded it. Chromium also
+        // uses the NoTrack id for every track.
+        "chromium" | "google-chrome" => Some(Quirks {
+            metadata_settle: Some(Duration::from_millis(300)),
+            placeholder_track_id: true,
+            ..Quirks::default()
+        }),
+        "firefox" => Some(Quirks {
+            metadata_settle: Some(Duration::from_millis(300)),
+            ..Quirks::default()
+        }),
+        _ => None,
+    }
+}
+
+fn overrides() -> &'static RwLock<HashMap<String, Quirks>> {
+    static OVERRIDES: OnceLock<RwLock<HashMap<String, Quirks>>> = OnceLock::new();
+    OVERRIDES.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// Sets the quirks for the player with the identity or desktop entry `key`, taking precedence
+/// over the built in table. Applies to streams started and control methods called afterwards.
+pub fn set_quirks(key: &str, quirks: Quirks) {
+    overrides().write().unwrap().insert(key.to_lowercase(), quirks);
+}
+
+/// Removes an override set with [`set_quirks`], going back to the built in table.
+pub fn clear_quirks(key: &str) {
+    overrides().write().unwrap().remove(&key.to_lowercase());
+}
+
+/// Errors from loading a quirks file.
+#[cfg(feature = "quirks-config")]
+#[derive(Debug)]
+pub enum QuirksError {
+    /// The file could not be read.
+    Io(io::Error),
+    /// The file is not valid TOML, or has fields that are not quirks.
+    Toml(toml::de::Error),
+}
+
+#[cfg(feature = "quirks-config")]
+impl fmt::Display for QuirksError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuirksError::Io(e) => write!(f, "Could not read quirks: {}", e),
+            QuirksError::Toml(e) => write!(f, "Could not parse quirks: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "quirks-config")]
+impl std::error::Error for QuirksError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            QuirksError::Io(e) => Some(e),
+            QuirksError::Toml(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(feature = "quirks-config")]
+impl From<io::Error> for QuirksError {
+    fn from(value: io::Error) -> Self {
+        QuirksError::Io(value)
+    }
+}
+
+#[cfg(feature = "quirks-config")]
+impl From<toml::de::Error> for QuirksError {
+    fn from(value: toml::de::Error) -> Self {
+        QuirksError::Toml(value)
+    }
+}
+
+#[cfg(feature = "quirks-config")]
+fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    return Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_millis));
+}
+
+/// Parses quirks from TOML, one table per identity or desktop entry. Missing fields keep their
+/// default:
+///
+/// ```toml
+/// [spotify]
+/// unreliable_position = true
+/// missing_seeked = true
+/// metadata_settle_ms = 300
+///
+/// [my-player]
+/// placeholder_track_id = true
+/// volume_scale = 100.0
+/// ```
+#[cfg(feature = "quirks-config")]
+pub fn parse_quirks(toml: &str) -> Result<HashMap<String, Quirks>, QuirksError> {
+    let table: HashMap<String, Quirks> = toml::from_str(toml)?;
+    return Ok(table.into_iter().map(|(key, quirks)| (key.to_lowercase(), quirks)).collect());
+}
+
+/// Reads a quirks file, see [`parse_quirks`], and sets every entry with [`set_quirks`]. Returns
+/// how many players were set.
+#[cfg(feature = "quirks-config")]
+pub fn load_quirks(path: impl AsRef<Path>) -> Result<usize, QuirksError> {
+    let table = parse_quirks(&fs::read_to_string(path)?)?;
+    let count = table.len();
+    for (key, quirks) in table {
+        set_quirks(&key, quirks);
+    }
+    return Ok(count);
+}
+
+/// The quirks for the identity or desktop entry `key`, without looking at a player.
+pub fn quirks_for_key(key: &str) -> Option<Quirks> {
+    let key = key.to_lowercase();
+    if let Some(quirks) = overrides().read().unwrap().get(&key) {
+        return Some(quirks.clone());
+    }
+    return builtin(&key);
+}
+
+/// The quirks of `player`, looked up by identity first and desktop entry second.
+pub fn quirks_for(player: &Player) -> Quirks {
+    if let Some(quirks) = quirks_for_key(player.identity()) {
+        return quirks;
+    }
+    return match player.get_desktop_entry() {
+        Ok(Some(entry)) => quirks_for_key(&entry).unwrap_or_default(),
+        _ => Quirks::default(),
+    };
+}
+
+impl PlayerHandle {
+    /// The quirks of the player.
+    pub async fn quirks(&self) -> Result<Quirks, Error> {
+        self.run(|player| Ok::<Quirks, Error>(quirks_for(player))).await
+    }
+}
+
+#[cfg(all(test, feature = "quirks-config"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_quirks_file() {
+        let table = parse_quirks(r#"
+            [Spotify]
+            unreliable_position = true
+            metadata_settle_ms = 300
+
+            [my-player]
+            placeholder_track_id = true
+            volume_scale = 100.0
+        "#).unwrap();
+        assert_eq!(table["spotify"], Quirks {
+            unreliable_position: true,
+            metadata_settle: Some(Duration::from_millis(300)),
+            ..Quirks::default()
+        });
+        assert_eq!(table["my-player"], Quirks { placeholder_track_id: true, volume_scale: Some(100.0), ..Quirks::default() });
+    }
+
+    #[test]
+    fn rejects_unknown_quirks() {
+        assert!(matches!(parse_quirks("[mpv]\nvolume_range = 100.0\n"), Err(QuirksError::Toml(_))));
+    }
+
+    #[test]
+    fn scales_volume() {
+        let quirks = Quirks { volume_scale: Some(100.0), ..Quirks::default() };
+        assert_eq!(quirks.normalize_volume(50.0), 0.5);
+        assert_eq!(quirks.denormalize_volume(0.25), 25.0);
+        assert_eq!(Quirks::default().normalize_volume(0.5), 0.5);
+        assert!(matches!(quirks.normalize_event(Event::VolumeChanged(80.0)), Event::VolumeChanged(x) if x == 0.8));
+    }
+}
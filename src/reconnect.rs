@@ -0,0 +1,44 @@
+//! Helpers for recovering from a lost DBus connection, used by the listener threads and the
+//! [`crate::player::PlayerStream`] watcher.
+
+use std::time::Duration;
+
+const INITIAL_DELAY: Duration = Duration::from_millis(100);
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// How many times a listener looks for the player again after reconnecting before it decides
+/// that the player is gone.
+pub(crate) const PLAYER_LOOKUP_ATTEMPTS: u32 = 5;
+
+/// Exponential backoff between reconnection attempts. Starts at 100ms and doubles up to 10s.
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    current: Duration,
+    attempts: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { current: INITIAL_DELAY, attempts: 0 }
+    }
+}
+
+impl Backoff {
+    /// Returns how long to wait before the next attempt and increases the delay.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(MAX_DELAY);
+        self.attempts += 1;
+        return delay;
+    }
+
+    /// How many attempts have been made since the last reset.
+    pub(crate) fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Call after a successful connection.
+    pub(crate) fn reset(&mut self) {
+        *self = Backoff::default();
+    }
+}
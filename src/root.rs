@@ -0,0 +1,147 @@
+//! Async access to the [`org.mpris.MediaPlayer2`][root] root interface of a player, and
+//! [`open_in_supporting_player`] to hand a file to whichever player can play it.
+//!
+//! [root]: https://specifications.freedesktop.org/mpris-spec/latest/Media_Player.html
+
+use dbus::{arg::{prop_cast, PropMap}, ffidisp::stdintf::org_freedesktop_dbus::Properties};
+
+use crate::{connection::BusConfig, error::Error, handle::{PlayerHandle, PLAYER_INTERFACE}};
+
+pub(crate) const ROOT_INTERFACE: &str = "org.mpris.MediaPlayer2";
+
+/// A change to a property of the root interface. Delivered by
+/// [`PlayerEventsStream`](crate::events::PlayerEventsStream) as
+/// [`PlayerEvent::Root`](crate::events::PlayerEvent::Root).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootEvent {
+    /// The player entered or left fullscreen.
+    FullscreenChanged(bool),
+    /// `CanRaise` changed.
+    CanRaiseChanged(bool),
+    /// `CanQuit` changed.
+    CanQuitChanged(bool),
+    /// `CanSetFullscreen` changed.
+    CanSetFullscreenChanged(bool),
+    /// The URI schemes the player can open changed.
+    SupportedUriSchemesChanged(Vec<String>),
+    /// The MIME types the player can open changed.
+    SupportedMimeTypesChanged(Vec<String>),
+}
+
+impl RootEvent {
+    /// Reads the events out of a `PropertiesChanged` signal for the root interface.
+    pub(crate) fn from_changed(changed: &PropMap) -> Vec<RootEvent> {
+        let mut events = vec![];
+        if let Some(x) = prop_cast::<bool>(changed, "Fullscreen") {
+            events.push(RootEvent::FullscreenChanged(*x));
+        }
+        if let Some(x) = prop_cast::<bool>(changed, "CanRaise") {
+            events.push(RootEvent::CanRaiseChanged(*x));
+        }
+        if let Some(x) = prop_cast::<bool>(changed, "CanQuit") {
+            events.push(RootEvent::CanQuitChanged(*x));
+        }
+        if let Some(x) = prop_cast::<bool>(changed, "CanSetFullscreen") {
+            events.push(RootEvent::CanSetFullscreenChanged(*x));
+        }
+        if let Some(x) = prop_cast::<Vec<String>>(changed, "SupportedUriSchemes") {
+            events.push(RootEvent::SupportedUriSchemesChanged(x.clone()));
+        }
+        if let Some(x) = prop_cast::<Vec<String>>(changed, "SupportedMimeTypes") {
+            events.push(RootEvent::SupportedMimeTypesChanged(x.clone()));
+        }
+        return events;
+    }
+}
+
+impl PlayerHandle {
+    /// Brings the player's user interface to the front.
+    ///
+    /// See: [`Player::raise`](mpris::Player::raise)
+    pub async fn raise(&self) -> Result<(), Error> {
+        self.run(|player| player.raise()).await
+    }
+
+    /// Asks the player to quit.
+    ///
+    /// See: [`Player::quit`](mpris::Player::quit)
+    pub async fn quit(&self) -> Result<(), Error> {
+        self.run(|player| player.quit()).await
+    }
+
+    /// Asks the player to open and play `uri`. Check [`supported_uri_schemes`](Self::supported_uri_schemes)
+    /// and [`supported_mime_types`](Self::supported_mime_types) first.
+    pub async fn open_uri(&self, uri: &str) -> Result<(), Error> {
+        let uri = uri.to_string();
+        self.run_raw(move |path| path.method_call(PLAYER_INTERFACE, "OpenUri", (uri,))).await
+    }
+
+    /// Whether the player is fullscreen. Returns [`None`] for players that do not support
+    /// fullscreen.
+    pub async fn get_fullscreen(&self) -> Result<Option<bool>, Error> {
+        self.run_raw(|path| optional_property(path.get(ROOT_INTERFACE, "Fullscreen"))).await
+    }
+
+    /// Asks the player to enter or leave fullscreen. Check
+    /// [`can_set_fullscreen`](Self::can_set_fullscreen) first.
+    pub async fn set_fullscreen(&self, fullscreen: bool) -> Result<(), Error> {
+        self.run_raw(move |path| path.set(ROOT_INTERFACE, "Fullscreen", fullscreen)).await
+    }
+
+    /// Whether [`raise`](Self::raise) does anything.
+    pub async fn can_raise(&self) -> Result<bool, Error> {
+        self.run(|player| player.can_raise()).await
+    }
+
+    /// Whether [`quit`](Self::quit) does anything.
+    pub async fn can_quit(&self) -> Result<bool, Error> {
+        self.run(|player| player.can_quit()).await
+    }
+
+    /// Whether [`set_fullscreen`](Self::set_fullscreen) does anything. Players that predate
+    /// fullscreen support return false.
+    pub async fn can_set_fullscreen(&self) -> Result<bool, Error> {
+        self.run_raw(|path| optional_property(path.get(ROOT_INTERFACE, "CanSetFullscreen")).map(|x| x.unwrap_or(false))).await
+    }
+
+    /// The URI schemes the player can open, such as `file` or `http`.
+    pub async fn supported_uri_schemes(&self) -> Result<Vec<String>, Error> {
+        self.run(|player| player.get_supported_uri_schemes()).await
+    }
+
+    /// The MIME types the player can open, such as `audio/mpeg`.
+    pub async fn supported_mime_types(&self) -> Result<Vec<String>, Error> {
+        self.run(|player| player.get_supported_mime_types()).await
+    }
+}
+
+/// Treats a missing property as [`None`], for properties that were added in later versions of
+/// the MPRIS spec.
+fn optional_property<T>(result: Result<T, dbus::Error>) -> Result<Option<T>, dbus::Error> {
+    match result {
+        Ok(x) => Ok(Some(x)),
+        Err(e) if e.name() == Some("org.freedesktop.DBus.Error.InvalidArgs") => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Opens `uri` in the first player on `bus` that supports both the scheme of `uri` and
+/// `mime_type`. Returns the player that was used, or [`None`] if no player supports it.
+pub async fn open_in_supporting_player(bus: &BusConfig, uri: &str, mime_type: &str) -> Result<Option<PlayerHandle>, Error> {
+    let scheme = uri.split(':').next().unwrap_or_default().to_lowercase();
+    for handle in PlayerHandle::find_all(bus).await? {
+        let mime_types = match handle.supported_mime_types().await {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let schemes = match handle.supported_uri_schemes().await {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if mime_types.iter().any(|x| x.eq_ignore_ascii_case(mime_type)) && schemes.iter().any(|x| x.eq_ignore_ascii_case(&scheme)) {
+            handle.open_uri(uri).await?;
+            return Ok(Some(handle));
+        }
+    }
+    return Ok(None);
+}
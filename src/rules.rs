@@ -0,0 +1,612 @@
+//! A rules engine that reacts to what players do. Rules are written in TOML and run on top of
+//! [`MergedEventsStream`]:
+//!
+//! ```toml
+//! [[rule]]
+//! name = "Mute Spotify ads"
+//! trigger = { event = "track_changed" }
+//! when = { identity = "spotify", url_prefix = "spotify:ad:" }
+//! actions = [{ volume = 0.0 }]
+//!
+//! [[rule]]
+//! name = "Quieter Spotify while mpv plays"
+//! trigger = { event = "playing" }
+//! when = { identity = "mpv" }
+//! target = "spotify"
+//! actions = [{ volume = 0.2 }]
+//!
+//! [[rule]]
+//! name = "Bedtime"
+//! trigger = { time = "23:00" }
+//! when = { status = "playing" }
+//! actions = ["pause"]
+//! ```
+//!
+//! Needs the `rules` feature.
+
+use std::{collections::HashMap, fmt, fs, io, path::Path, process::Command, time::Duration};
+
+use async_std::{future, stream::StreamExt, task};
+use chrono::{Local, Timelike};
+use mpris::{Event, Metadata, PlaybackStatus};
+use serde::Deserialize;
+
+use crate::{connection::BusConfig, error::Error, events::{MergedEvent, MergedEventsStream, PlayerEvent}, handle::PlayerHandle};
+
+/// Longest time between checks of the time triggers.
+const CLOCK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most minutes of time triggers caught up on after the clock was not looked at for a while.
+/// Larger jumps, such as waking from sleep or the clock being set back, only fire the current
+/// minute.
+const MAX_MISSED_MINUTES: u32 = 60;
+
+/// Errors from loading rules.
+#[derive(Debug)]
+pub enum RulesError {
+    /// The rules file could not be read.
+    Io(io::Error),
+    /// The rules are not valid TOML, or don't have the expected fields.
+    Toml(toml::de::Error),
+    /// A rule makes no sense, such as a time that is not `HH:MM`.
+    Invalid { rule: String, reason: String },
+}
+
+impl fmt::Display for RulesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RulesError::Io(e) => write!(f, "Could not read rules: {}", e),
+            RulesError::Toml(e) => write!(f, "Could not parse rules: {}", e),
+            RulesError::Invalid { rule, reason } => write!(f, "Invalid rule \"{}\": {}", rule, reason),
+        }
+    }
+}
+
+impl std::error::Error for RulesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RulesError::Io(e) => Some(e),
+            RulesError::Toml(e) => Some(e),
+            RulesError::Invalid { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for RulesError {
+    fn from(e: io::Error) -> Self {
+        RulesError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for RulesError {
+    fn from(e: toml::de::Error) -> Self {
+        RulesError::Toml(e)
+    }
+}
+
+/// What makes a rule run.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// A player did something. The rule runs for that player.
+    Event(EventKind),
+    /// The players as a whole changed state. The rule runs for every player.
+    State(StateKind),
+    /// Every day at a local time, written `HH:MM`. The rule runs for every player.
+    Time(String),
+}
+
+/// Events a rule can react to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Appeared,
+    Playing,
+    Paused,
+    Stopped,
+    TrackChanged,
+    Seeked,
+    VolumeChanged,
+    Quit,
+}
+
+impl EventKind {
+    fn from_event(event: &Event) -> Option<Self> {
+        match event {
+            Event::Playing => Some(EventKind::Playing),
+            Event::Paused => Some(EventKind::Paused),
+            Event::Stopped => Some(EventKind::Stopped),
+            Event::TrackChanged(_) => Some(EventKind::TrackChanged),
+            Event::Seeked { .. } => Some(EventKind::Seeked),
+            Event::VolumeChanged(_) => Some(EventKind::VolumeChanged),
+            Event::PlayerShutDown => Some(EventKind::Quit),
+            _ => None,
+        }
+    }
+}
+
+/// States of all players together that a rule can react to. The rule runs when the state
+/// starts to hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateKind {
+    /// At least one player is playing.
+    AnyPlaying,
+    /// No player is playing.
+    NonePlaying,
+}
+
+/// A playback status in a condition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+impl From<Status> for PlaybackStatus {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Playing => PlaybackStatus::Playing,
+            Status::Paused => PlaybackStatus::Paused,
+            Status::Stopped => PlaybackStatus::Stopped,
+        }
+    }
+}
+
+/// What a player must look like for a rule to run for it. Every field that is set must match.
+/// Text is compared ignoring case.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Conditions {
+    /// The identity of the player.
+    pub identity: Option<String>,
+    pub title_contains: Option<String>,
+    /// Matches if any artist contains the text.
+    pub artist_contains: Option<String>,
+    pub album_contains: Option<String>,
+    pub url_prefix: Option<String>,
+    pub track_id_prefix: Option<String>,
+    pub status: Option<Status>,
+}
+
+fn contains(haystack: Option<&str>, needle: &str) -> bool {
+    haystack.map_or(false, |x| x.to_lowercase().contains(&needle.to_lowercase()))
+}
+
+impl Conditions {
+    /// Whether `player` matches every condition.
+    fn matches(&self, player: &PlayerState) -> bool {
+        let metadata = &player.metadata;
+        let checks = [
+            self.identity.as_ref().map(|x| x.eq_ignore_ascii_case(player.handle.identity())),
+            self.title_contains.as_ref().map(|x| contains(metadata.title(), x)),
+            self.artist_contains.as_ref().map(|x| metadata.artists().unwrap_or_default().iter().any(|a| contains(Some(*a), x))),
+            self.album_contains.as_ref().map(|x| contains(metadata.album_name(), x)),
+            self.url_prefix.as_ref().map(|x| metadata.url().map_or(false, |url| url.starts_with(x.as_str()))),
+            self.track_id_prefix.as_ref().map(|x| metadata.track_id().map_or(false, |id| id.as_str().starts_with(x.as_str()))),
+            self.status.map(|x| player.status == PlaybackStatus::from(x)),
+        ];
+        return checks.iter().all(|x| x.unwrap_or(true));
+    }
+}
+
+/// Something a rule does. Written as a plain string, like `"pause"`, or a table with a value,
+/// like `{ volume = 0.2 }`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Play,
+    Pause,
+    PlayPause,
+    Stop,
+    Next,
+    Previous,
+    /// Sets the volume, in the 0.0-1.0 range.
+    Volume(f64),
+    /// Seeks by this many seconds, which may be negative.
+    Seek(f64),
+    /// Runs a command with `sh -c`. The player is described in the `MPRIS_IDENTITY`,
+    /// `MPRIS_BUS_NAME`, `MPRIS_STATUS`, `MPRIS_TITLE`, `MPRIS_ARTIST`, `MPRIS_ALBUM` and
+    /// `MPRIS_URL` environment variables. The rule does not wait for it to finish.
+    Command(String),
+}
+
+/// A rule: when `trigger` happens to a player that matches `when` and does not match `unless`,
+/// run `actions` on `target`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub trigger: Trigger,
+    #[serde(default)]
+    pub when: Conditions,
+    #[serde(default)]
+    pub unless: Option<Conditions>,
+    /// Which players the actions are run on: `"self"` for the player the rule runs for, which is
+    /// the default, `"all"`, `"others"`, or the identity of a player.
+    #[serde(default = "default_target")]
+    pub target: String,
+    pub actions: Vec<Action>,
+}
+
+fn default_target() -> String {
+    "self".to_string()
+}
+
+impl Rule {
+    fn applies_to(&self, player: &PlayerState) -> bool {
+        self.when.matches(player) && !self.unless.as_ref().map_or(false, |x| x.matches(player))
+    }
+
+    fn validate(&self) -> Result<(), RulesError> {
+        if let Trigger::Time(time) = &self.trigger {
+            if parse_time(time).is_none() {
+                return Err(RulesError::Invalid { rule: self.name.clone(), reason: format!("\"{}\" is not a time like 23:00", time) });
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Reads `HH:MM` into hours and minutes.
+fn parse_time(time: &str) -> Option<(u32, u32)> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes) = (hours.trim().parse().ok()?, minutes.trim().parse().ok()?);
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+    return Some((hours, minutes));
+}
+
+#[derive(Deserialize)]
+struct RulesFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+/// What the engine knows about a player.
+#[derive(Debug, Clone)]
+struct PlayerState {
+    handle: PlayerHandle,
+    status: PlaybackStatus,
+    metadata: Metadata,
+}
+
+/// Runs [`Rule`]s against the events of every player.
+#[derive(Debug, Clone, Default)]
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+}
+
+impl RuleEngine {
+    /// Creates an engine with the given rules.
+    pub fn new(rules: Vec<Rule>) -> Result<Self, RulesError> {
+        for rule in &rules {
+            rule.validate()?;
+        }
+        return Ok(RuleEngine { rules });
+    }
+
+    /// Reads rules written as `[[rule]]` tables.
+    pub fn from_toml(toml: &str) -> Result<Self, RulesError> {
+        let file: RulesFile = toml::from_str(toml)?;
+        return RuleEngine::new(file.rules);
+    }
+
+    /// Reads rules from a TOML file, see [`from_toml`](Self::from_toml).
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RulesError> {
+        return RuleEngine::from_toml(&fs::read_to_string(path)?);
+    }
+
+    /// The rules of the engine.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Runs the rules against every player on `bus` until the connection to DBus is lost for
+    /// good. Players are picked up as they appear, checking every `retry_delay` milliseconds.
+    /// Actions run in the background, so a slow player does not hold up the others.
+    pub async fn run(&self, bus: BusConfig, retry_delay: u64) {
+        let mut events = MergedEventsStream::with_bus(retry_delay, bus);
+        let mut players: HashMap<String, PlayerState> = HashMap::new();
+        let mut any_playing = false;
+        let mut last_minute = None;
+
+        loop {
+            match future::timeout(CLOCK_INTERVAL, events.next()).await {
+                Ok(Some(MergedEvent::Appeared(handle))) => {
+                    let state = match read_state(&handle).await {
+                        Ok(x) => x,
+                        Err(_) => continue,
+                    };
+                    players.insert(handle.bus_name().to_string(), state.clone());
+                    self.fire_event(EventKind::Appeared, &state, &players);
+                },
+                Ok(Some(MergedEvent::Event(handle, PlayerEvent::Player(event)))) => {
+                    let key = handle.bus_name().to_string();
+                    if let Some(state) = players.get_mut(&key) {
+                        match &event {
+                            Event::Playing => state.status = PlaybackStatus::Playing,
+                            Event::Paused => state.status = PlaybackStatus::Paused,
+                            Event::Stopped => state.status = PlaybackStatus::Stopped,
+                            Event::TrackChanged(metadata) => state.metadata = metadata.clone(),
+                            _ => {},
+                        }
+                    }
+                    if let (Some(state), Some(kind)) = (players.get(&key), EventKind::from_event(&event)) {
+                        self.fire_event(kind, state, &players);
+                    }
+                    if let Event::PlayerShutDown = event {
+                        players.remove(&key);
+                    }
+                },
+                // Anything that changed while disconnected was missed
+                Ok(Some(MergedEvent::Event(handle, PlayerEvent::Reconnected))) => {
+                    if let Ok(state) = read_state(&handle).await {
+                        players.insert(handle.bus_name().to_string(), state);
+                    }
+                },
+                Ok(Some(MergedEvent::Event(_, PlayerEvent::Root(_)))) => {},
+                Ok(None) => return,
+                // Only here to look at the clock
+                Err(_) => {},
+            }
+
+            let now = Local::now();
+            let minute = (now.hour(), now.minute());
+            if last_minute != Some(minute) {
+                // Not on the first pass, a rule for the current minute has already been missed
+                if let Some(last) = last_minute {
+                    for passed in minutes_since(last, minute) {
+                        self.fire_time(passed, &players);
+                    }
+                }
+                last_minute = Some(minute);
+            }
+
+            let playing = players.values().any(|x| x.status == PlaybackStatus::Playing);
+            if playing != any_playing {
+                any_playing = playing;
+                self.fire_state(if playing { StateKind::AnyPlaying } else { StateKind::NonePlaying }, &players);
+            }
+        }
+    }
+
+    fn fire_event(&self, kind: EventKind, player: &PlayerState, players: &HashMap<String, PlayerState>) {
+        for rule in self.rules.iter().filter(|x| x.trigger == Trigger::Event(kind)) {
+            self.fire(rule, player, players);
+        }
+    }
+
+    fn fire_state(&self, kind: StateKind, players: &HashMap<String, PlayerState>) {
+        for rule in self.rules.iter().filter(|x| x.trigger == Trigger::State(kind)) {
+            for player in players.values() {
+                self.fire(rule, player, players);
+            }
+        }
+    }
+
+    fn fire_time(&self, minute: (u32, u32), players: &HashMap<String, PlayerState>) {
+        for rule in &self.rules {
+            match &rule.trigger {
+                Trigger::Time(time) if parse_time(time) == Some(minute) => {
+                    for player in players.values() {
+                        self.fire(rule, player, players);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Runs the actions of `rule` for `player`, if it applies.
+    fn fire(&self, rule: &Rule, player: &PlayerState, players: &HashMap<String, PlayerState>) {
+        if !rule.applies_to(player) {
+            return;
+        }
+        let targets = targets(rule, player, players);
+        let actions = rule.actions.clone();
+        task::spawn(async move {
+            for target in &targets {
+                for action in &actions {
+                    let _ = run_action(action, target).await;
+                }
+            }
+        });
+    }
+}
+
+/// The players the actions of `rule` run on when it fires for `player`.
+fn targets(rule: &Rule, player: &PlayerState, players: &HashMap<String, PlayerState>) -> Vec<PlayerState> {
+    return players.values().filter(|x| match rule.target.as_str() {
+        "self" => x.handle == player.handle,
+        "all" => true,
+        "others" => x.handle != player.handle,
+        identity => x.handle.identity().eq_ignore_ascii_case(identity),
+    }).cloned().collect();
+}
+
+/// The minutes after `last` up to and including `now`, going past midnight if needed.
+fn minutes_since(last: (u32, u32), now: (u32, u32)) -> Vec<(u32, u32)> {
+    let (last, now) = (last.0 * 60 + last.1, now.0 * 60 + now.1);
+    let passed = (now + 24 * 60 - last) % (24 * 60);
+    if passed > MAX_MISSED_MINUTES {
+        return vec![(now / 60, now % 60)];
+    }
+    return (1..=passed).map(|x| (last + x) % (24 * 60)).map(|x| (x / 60, x % 60)).collect();
+}
+
+async fn read_state(handle: &PlayerHandle) -> Result<PlayerState, Error> {
+    let (status, metadata) = handle.run(|player| Ok::<_, Error>((player.get_playback_status()?, player.get_metadata()?))).await?;
+    return Ok(PlayerState { handle: handle.clone(), status, metadata });
+}
+
+async fn run_action(action: &Action, player: &PlayerState) -> Result<(), Error> {
+    let handle = &player.handle;
+    return match action {
+        Action::Play => handle.play().await,
+        Action::Pause => handle.pause().await,
+        Action::PlayPause => handle.play_pause().await,
+        Action::Stop => handle.stop().await,
+        Action::Next => handle.next().await,
+        Action::Previous => handle.previous().await,
+        Action::Volume(volume) => handle.set_volume(*volume).await,
+        Action::Seek(seconds) => handle.seek((seconds * 1_000_000.0) as i64).await,
+        Action::Command(command) => {
+            let metadata = &player.metadata;
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("MPRIS_IDENTITY", handle.identity())
+                .env("MPRIS_BUS_NAME", handle.bus_name())
+                .env("MPRIS_STATUS", format!("{:?}", player.status))
+                .env("MPRIS_TITLE", metadata.title().unwrap_or_default())
+                .env("MPRIS_ARTIST", metadata.artists().unwrap_or_default().join(", "))
+                .env("MPRIS_ALBUM", metadata.album_name().unwrap_or_default())
+                .env("MPRIS_URL", metadata.url().unwrap_or_default())
+                .spawn();
+            if let Ok(mut child) = child {
+                // Reaped in the background so it does not linger as a zombie
+                task::spawn_blocking(move || child.wait());
+            }
+            Ok(())
+        },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use mpris::MetadataValue;
+
+    use super::*;
+
+    fn player(identity: &str, status: PlaybackStatus, fields: &[(&str, MetadataValue)]) -> PlayerState {
+        let metadata = fields.iter().map(|(key, value)| (key.to_string(), value.clone())).collect::<HashMap<_, _>>();
+        PlayerState { handle: PlayerHandle::from_parts(identity, &format!("org.mpris.MediaPlayer2.{}", identity)), status, metadata: Metadata::from(metadata) }
+    }
+
+    fn spotify_ad() -> PlayerState {
+        player("Spotify", PlaybackStatus::Playing, &[
+            ("xesam:title", MetadataValue::String("Advertisement".to_string())),
+            ("xesam:artist", MetadataValue::Array(vec![MetadataValue::String("Spotify".to_string())])),
+            ("xesam:url", MetadataValue::String("spotify:ad:123".to_string())),
+        ])
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("23:00"), Some((23, 0)));
+        assert_eq!(parse_time("7:05"), Some((7, 5)));
+        assert_eq!(parse_time(" 00 : 59 "), Some((0, 59)));
+        assert_eq!(parse_time("24:00"), None);
+        assert_eq!(parse_time("12:60"), None);
+        assert_eq!(parse_time("1200"), None);
+        assert_eq!(parse_time("noon"), None);
+        assert_eq!(parse_time("-1:30"), None);
+    }
+
+    #[test]
+    fn empty_conditions_match_anything() {
+        assert!(Conditions::default().matches(&spotify_ad()));
+        assert!(Conditions::default().matches(&player("mpv", PlaybackStatus::Stopped, &[])));
+    }
+
+    #[test]
+    fn every_condition_must_match() {
+        let ad = spotify_ad();
+        let conditions = Conditions {
+            identity: Some("spotify".to_string()),
+            url_prefix: Some("spotify:ad:".to_string()),
+            artist_contains: Some("SPOT".to_string()),
+            status: Some(Status::Playing),
+            ..Conditions::default()
+        };
+        assert!(conditions.matches(&ad));
+
+        assert!(!Conditions { status: Some(Status::Paused), ..conditions.clone() }.matches(&ad));
+        assert!(!Conditions { identity: Some("mpv".to_string()), ..conditions.clone() }.matches(&ad));
+        // Prefixes are compared as written, unlike the text conditions
+        assert!(!Conditions { url_prefix: Some("SPOTIFY:".to_string()), ..conditions.clone() }.matches(&ad));
+        // Missing metadata never matches a condition on it
+        assert!(!Conditions { album_contains: Some("a".to_string()), ..conditions }.matches(&ad));
+    }
+
+    #[test]
+    fn parses_the_documented_example() {
+        let example = r#"
+            [[rule]]
+            name = "Mute Spotify ads"
+            trigger = { event = "track_changed" }
+            when = { identity = "spotify", url_prefix = "spotify:ad:" }
+            actions = [{ volume = 0.0 }]
+
+            [[rule]]
+            name = "Quieter Spotify while mpv plays"
+            trigger = { event = "playing" }
+            when = { identity = "mpv" }
+            target = "spotify"
+            actions = [{ volume = 0.2 }]
+
+            [[rule]]
+            name = "Bedtime"
+            trigger = { time = "23:00" }
+            when = { status = "playing" }
+            actions = ["pause"]
+        "#;
+        let engine = RuleEngine::from_toml(example).unwrap();
+        let rules = engine.rules();
+        assert_eq!(rules.len(), 3);
+
+        assert_eq!(rules[0].trigger, Trigger::Event(EventKind::TrackChanged));
+        assert_eq!(rules[0].when.url_prefix.as_deref(), Some("spotify:ad:"));
+        assert_eq!(rules[0].target, "self");
+        assert_eq!(rules[0].actions, vec![Action::Volume(0.0)]);
+
+        assert_eq!(rules[1].trigger, Trigger::Event(EventKind::Playing));
+        assert_eq!(rules[1].target, "spotify");
+        assert_eq!(rules[1].actions, vec![Action::Volume(0.2)]);
+
+        assert_eq!(rules[2].trigger, Trigger::Time("23:00".to_string()));
+        assert_eq!(rules[2].when, Conditions { status: Some(Status::Playing), ..Conditions::default() });
+        assert_eq!(rules[2].actions, vec![Action::Pause]);
+    }
+
+    #[test]
+    fn rejects_bad_times() {
+        let rules = "[[rule]]\nname = \"Late\"\ntrigger = { time = \"25:00\" }\nactions = [\"pause\"]\n";
+        assert!(matches!(RuleEngine::from_toml(rules), Err(RulesError::Invalid { .. })));
+    }
+
+    #[test]
+    fn targets_resolve_to_players() {
+        let players = [player("Spotify", PlaybackStatus::Playing, &[]), player("mpv", PlaybackStatus::Paused, &[]), player("vlc", PlaybackStatus::Stopped, &[])]
+            .into_iter()
+            .map(|x| (x.handle.bus_name().to_string(), x))
+            .collect::<HashMap<_, _>>();
+        let mpv = &players["org.mpris.MediaPlayer2.mpv"];
+        let resolve = |target: &str| {
+            let rule = Rule { name: "Test".to_string(), trigger: Trigger::Event(EventKind::Playing), when: Conditions::default(), unless: None, target: target.to_string(), actions: vec![] };
+            let mut identities = targets(&rule, mpv, &players).iter().map(|x| x.handle.identity().to_string()).collect::<Vec<_>>();
+            identities.sort();
+            identities
+        };
+        assert_eq!(resolve("self"), vec!["mpv"]);
+        assert_eq!(resolve("others"), vec!["Spotify", "vlc"]);
+        assert_eq!(resolve("all"), vec!["Spotify", "mpv", "vlc"]);
+        // Identities are compared ignoring case
+        assert_eq!(resolve("spotify"), vec!["Spotify"]);
+        assert!(resolve("rhythmbox").is_empty());
+    }
+
+    #[test]
+    fn catches_up_on_missed_minutes() {
+        assert_eq!(minutes_since((22, 59), (23, 0)), vec![(23, 0)]);
+        assert_eq!(minutes_since((22, 58), (23, 1)), vec![(22, 59), (23, 0), (23, 1)]);
+        assert_eq!(minutes_since((23, 59), (0, 1)), vec![(0, 0), (0, 1)]);
+        // Waking from sleep, or the clock going back, does not fire everything in between
+        assert_eq!(minutes_since((8, 0), (23, 0)), vec![(23, 0)]);
+        assert_eq!(minutes_since((3, 0), (2, 0)), vec![(2, 0)]);
+    }
+}
@@ -0,0 +1,187 @@
+//! Detects players that report a bogus `Position`, such as Spotify. [`PositionSanity`] compares
+//! the positions a player reports with the time that passed in between, and can keep track of
+//! the position locally for players that fail.
+
+use std::time::{Duration, Instant};
+
+use mpris::PlaybackStatus;
+
+use crate::fake_progress::ProgressClone;
+
+/// Samples closer together than this are too noisy to compare.
+const MIN_SAMPLE_GAP: Duration = Duration::from_millis(500);
+
+/// How far off a reported position may be, on top of [`RELATIVE_TOLERANCE`].
+const ABSOLUTE_TOLERANCE: Duration = Duration::from_millis(750);
+
+/// How far off a reported position may be, relative to the time between the samples.
+const RELATIVE_TOLERANCE: f64 = 0.1;
+
+/// How many samples in a row must agree before the verdict changes. An unannounced seek only
+/// causes a single bad sample.
+const SAMPLES_TO_DECIDE: u32 = 3;
+
+/// Whether the `Position` a player reports can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionReliability {
+    /// Not enough samples yet, such as while the player was not playing.
+    #[default]
+    Unknown,
+    /// The reported position moves along with time.
+    Reliable,
+    /// The reported position does not move along with time. It might always be 0, or be
+    /// updated much less often than the player claims.
+    Unreliable,
+}
+
+/// A position reported by the player.
+#[derive(Debug, Clone)]
+struct Sample {
+    track: Option<String>,
+    position: Duration,
+    instant: Instant,
+    rate: f64,
+}
+
+/// The position as kept by the local wall clock, anchored on track changes, seeks and changes
+/// to the playback status or rate.
+#[derive(Debug, Clone)]
+struct LocalClock {
+    track: Option<String>,
+    position: Duration,
+    instant: Instant,
+    rate: f64,
+    playing: bool,
+}
+
+impl LocalClock {
+    fn estimate(&self) -> Duration {
+        if !self.playing {
+            return self.position;
+        }
+        return self.position + self.instant.elapsed().mul_f64(self.rate.max(0.0));
+    }
+
+    fn anchor(&mut self, position: Duration) {
+        self.position = position;
+        self.instant = Instant::now();
+    }
+}
+
+/// Checks successive [`ProgressClone`]s of one player against the time that passed between
+/// them. Used by [`crate::progress::ProgressStream`], but can be fed by hand as well.
+#[derive(Debug, Clone, Default)]
+pub struct PositionSanity {
+    reliability: PositionReliability,
+    fallback: bool,
+    last: Option<Sample>,
+    good: u32,
+    bad: u32,
+    clock: Option<LocalClock>,
+}
+
+impl PositionSanity {
+    /// Creates a new [`PositionSanity`]. If `fallback` is true, positions of a player found to be
+    /// [`PositionReliability::Unreliable`] are replaced by the locally tracked position.
+    pub fn new(fallback: bool) -> Self {
+        PositionSanity { fallback, ..PositionSanity::default() }
+    }
+
+    /// The current verdict.
+    pub fn reliability(&self) -> PositionReliability {
+        self.reliability
+    }
+
+    /// Starts out as [`PositionReliability::Unreliable`], for players known to misreport their
+    /// position. Enough good samples still make it [`PositionReliability::Reliable`].
+    pub fn assume_unreliable(&mut self) {
+        self.reliability = PositionReliability::Unreliable;
+    }
+
+    /// Looks at a new progress of the player and stores the verdict in it. If the player is
+    /// unreliable and the fallback is enabled, the position is replaced as well.
+    pub fn observe(&mut self, progress: &mut ProgressClone) {
+        self.update_clock(progress);
+        self.check(progress);
+        progress.reliability = self.reliability;
+        if self.fallback && self.reliability == PositionReliability::Unreliable {
+            if let Some(clock) = &self.clock {
+                progress.position = clock.estimate();
+                progress.instant = Instant::now();
+            }
+        }
+    }
+
+    /// Tells the checker about a `Seeked` signal, so the jump is not held against the player.
+    pub fn seeked(&mut self, position: Duration) {
+        self.last = None;
+        if let Some(clock) = &mut self.clock {
+            clock.anchor(position);
+        }
+    }
+
+    fn update_clock(&mut self, progress: &ProgressClone) {
+        let track = progress.track_key();
+        let playing = progress.playback_status() == PlaybackStatus::Playing;
+        let rate = progress.playback_rate();
+        match &mut self.clock {
+            Some(clock) if clock.track != track => {
+                clock.track = track;
+                clock.playing = playing;
+                clock.rate = rate;
+                clock.anchor(Duration::ZERO);
+            },
+            Some(clock) if clock.playing != playing || clock.rate != rate => {
+                let position = clock.estimate();
+                clock.playing = playing;
+                clock.rate = rate;
+                clock.anchor(position);
+            },
+            Some(_) => {},
+            // The first position is all there is to go on, even if it might be wrong
+            None => self.clock = Some(LocalClock { track, position: progress.position(), instant: Instant::now(), rate, playing }),
+        }
+    }
+
+    fn check(&mut self, progress: &ProgressClone) {
+        if progress.playback_status() != PlaybackStatus::Playing {
+            self.last = None;
+            return;
+        }
+        let sample = Sample {
+            track: progress.track_key(),
+            position: progress.initial_position(),
+            instant: *progress.created_at(),
+            rate: progress.playback_rate(),
+        };
+        let last = match &self.last {
+            Some(x) if x.track == sample.track && x.rate == sample.rate => x,
+            _ => {
+                self.last = Some(sample);
+                return;
+            },
+        };
+        let gap = match sample.instant.checked_duration_since(last.instant) {
+            Some(x) if x >= MIN_SAMPLE_GAP => x,
+            _ => return,
+        };
+
+        let expected = gap.as_secs_f64() * sample.rate;
+        let actual = sample.position.as_secs_f64() - last.position.as_secs_f64();
+        let tolerance = ABSOLUTE_TOLERANCE.as_secs_f64() + expected.abs() * RELATIVE_TOLERANCE;
+        if (actual - expected).abs() <= tolerance {
+            self.good += 1;
+            self.bad = 0;
+        } else {
+            self.bad += 1;
+            self.good = 0;
+        }
+        self.last = Some(sample);
+
+        if self.good >= SAMPLES_TO_DECIDE {
+            self.reliability = PositionReliability::Reliable;
+        } else if self.bad >= SAMPLES_TO_DECIDE {
+            self.reliability = PositionReliability::Unreliable;
+        }
+    }
+}
@@ -0,0 +1,460 @@
+//! Event hooks written in [Rhai](https://rhai.rs). Every script gets each event of every player
+//! through an `on_event` function, along with the player it came from:
+//!
+//! ```rhai
+//! fn init() {
+//!     #{ skipped: 0 }
+//! }
+//!
+//! fn on_event(event, player) {
+//!     if event.kind == "track_changed" && player.identity == "Spotify" && event.title == "Advertisement" {
+//!         player.set_volume(0.0);
+//!         this.skipped += 1;
+//!     }
+//! }
+//! ```
+//!
+//! `this` is the state of the script, kept between events. It starts as what `init` returns, or
+//! an empty map without one. Scripts can't touch files or the network, and each call gets a time
+//! and operation budget, see [`ScriptLimits`]. What scripts `print` or `debug` goes to
+//! [`ScriptHost::on_output`], or to stderr by default.
+//!
+//! Needs the `scripting` feature.
+
+use std::{fmt, fs, io, path::Path, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use async_std::{future, stream::StreamExt, task};
+use mpris::{Event, Metadata};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Position, Scope, AST, INT};
+
+use crate::{connection::BusConfig, events::{MergedEvent, MergedEventsStream, PlayerEvent}, handle::PlayerHandle};
+
+/// The function every script must have.
+const HOOK: &str = "on_event";
+
+/// The optional function that sets up the state of a script.
+const INIT: &str = "init";
+
+/// How much a script may do each time it is called.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptLimits {
+    /// Wall time per call, including time spent waiting for the player on control calls. A
+    /// control call still waiting when the budget runs out stops the script.
+    pub time_budget: Duration,
+    /// Operations per call, which stops endless loops even when the clock is not checked.
+    pub max_operations: u64,
+    /// How deep functions may call each other.
+    pub max_call_levels: usize,
+    /// Longest string a script may build, in bytes.
+    pub max_string_size: usize,
+    /// Most items an array or map may have.
+    pub max_collection_size: usize,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        ScriptLimits {
+            time_budget: Duration::from_millis(100),
+            max_operations: 100_000,
+            max_call_levels: 32,
+            max_string_size: 64 * 1024,
+            max_collection_size: 10_000,
+        }
+    }
+}
+
+/// Errors from loading or running scripts.
+#[derive(Debug)]
+pub enum ScriptError {
+    /// The script file could not be read.
+    Io(io::Error),
+    /// The script does not compile, or has no `on_event(event, player)` function.
+    Compile { script: String, message: String },
+    /// The script failed while handling an event.
+    Runtime { script: String, message: String },
+    /// The script ran out of time or operations and was stopped.
+    BudgetExceeded { script: String },
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Io(e) => write!(f, "Could not read script: {}", e),
+            ScriptError::Compile { script, message } => write!(f, "Script \"{}\" does not compile: {}", script, message),
+            ScriptError::Runtime { script, message } => write!(f, "Script \"{}\" failed: {}", script, message),
+            ScriptError::BudgetExceeded { script } => write!(f, "Script \"{}\" ran out of time and was stopped", script),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScriptError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ScriptError {
+    fn from(e: io::Error) -> Self {
+        ScriptError::Io(e)
+    }
+}
+
+/// Where script output goes, with the name of the script and the text.
+type OutputFn = dyn Fn(&str, &str) + Send + Sync;
+
+/// When the current call has to be done by, shared by the engine and the players it hands out.
+type Deadline = Arc<Mutex<Option<Instant>>>;
+
+/// The player as scripts see it. Control calls block the script until the player answers, or
+/// until the time budget of the call runs out.
+#[derive(Debug, Clone)]
+struct ScriptPlayer {
+    handle: PlayerHandle,
+    deadline: Deadline,
+}
+
+impl ScriptPlayer {
+    fn call<T, F, Fut>(&mut self, f: F) -> Result<T, Box<EvalAltResult>>
+    where
+        F: FnOnce(PlayerHandle) -> Fut,
+        Fut: std::future::Future<Output = Result<T, crate::error::Error>>,
+    {
+        let deadline = *self.deadline.lock().unwrap();
+        let result = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match task::block_on(future::timeout(remaining, f(self.handle.clone()))) {
+                    Ok(x) => x,
+                    // Reported as the budget running out, same as the engine stopping the script
+                    Err(_) => return Err(EvalAltResult::ErrorTerminated(Dynamic::UNIT, Position::NONE).into()),
+                }
+            },
+            None => task::block_on(f(self.handle.clone())),
+        };
+        return result.map_err(|e| e.to_string().into());
+    }
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+    state: Dynamic,
+}
+
+/// Runs scripts against player events. Scripts are called one after another, in the order they
+/// were added.
+pub struct ScriptHost {
+    engine: Engine,
+    scripts: Vec<Script>,
+    /// When the current call has to be done by, checked by the engine as it runs.
+    deadline: Deadline,
+    /// What the current call printed, handed to `output` once it returns.
+    printed: Arc<Mutex<Vec<String>>>,
+    output: Box<OutputFn>,
+    limits: ScriptLimits,
+}
+
+impl ScriptHost {
+    /// Creates a host without any scripts.
+    pub fn new(limits: ScriptLimits) -> Self {
+        let deadline: Deadline = Arc::new(Mutex::new(None));
+        let printed: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+        let mut engine = Engine::new();
+        engine.set_max_operations(limits.max_operations);
+        engine.set_max_call_levels(limits.max_call_levels);
+        engine.set_max_string_size(limits.max_string_size);
+        engine.set_max_array_size(limits.max_collection_size);
+        engine.set_max_map_size(limits.max_collection_size);
+        // Code from strings would get around compiling up front, and modules are read from disk
+        engine.disable_symbol("eval");
+        engine.disable_symbol("import");
+        let progress_deadline = deadline.clone();
+        engine.on_progress(move |_| match *progress_deadline.lock().unwrap() {
+            Some(deadline) if Instant::now() >= deadline => Some(Dynamic::UNIT),
+            _ => None,
+        });
+        // Scripts don't get to write to stdout, output is passed on with the name of the script
+        let print_buffer = printed.clone();
+        engine.on_print(move |text| print_buffer.lock().unwrap().push(text.to_string()));
+        let debug_buffer = printed.clone();
+        engine.on_debug(move |text, _, position| {
+            let line = match position.is_none() {
+                true => format!("[debug] {}", text),
+                false => format!("[debug] {} ({})", text, position),
+            };
+            debug_buffer.lock().unwrap().push(line);
+        });
+        ScriptHost::register_player(&mut engine);
+        let output: Box<OutputFn> = Box::new(|script, text| eprintln!("{}: {}", script, text));
+        return ScriptHost { engine, scripts: vec![], deadline, printed, output, limits };
+    }
+
+    /// Sends what scripts `print` and `debug` to `f`, called with the name of the script and the
+    /// text. Replaces the default of writing it to stderr.
+    pub fn on_output<F>(&mut self, f: F)
+    where
+        F: Fn(&str, &str) + Send + Sync + 'static,
+    {
+        self.output = Box::new(f);
+    }
+
+    fn register_player(engine: &mut Engine) {
+        engine.register_type_with_name::<ScriptPlayer>("Player");
+        engine.register_get("identity", |x: &mut ScriptPlayer| x.handle.identity().to_string());
+        engine.register_get("bus_name", |x: &mut ScriptPlayer| x.handle.bus_name().to_string());
+        engine.register_fn("play", |x: &mut ScriptPlayer| x.call(|h| async move { h.play().await }));
+        engine.register_fn("pause", |x: &mut ScriptPlayer| x.call(|h| async move { h.pause().await }));
+        engine.register_fn("play_pause", |x: &mut ScriptPlayer| x.call(|h| async move { h.play_pause().await }));
+        engine.register_fn("stop", |x: &mut ScriptPlayer| x.call(|h| async move { h.stop().await }));
+        engine.register_fn("next", |x: &mut ScriptPlayer| x.call(|h| async move { h.next().await }));
+        engine.register_fn("previous", |x: &mut ScriptPlayer| x.call(|h| async move { h.previous().await }));
+        engine.register_fn("set_volume", |x: &mut ScriptPlayer, volume: f64| x.call(|h| async move { h.set_volume(volume).await }));
+        engine.register_fn("seek", |x: &mut ScriptPlayer, seconds: f64| x.call(|h| async move { h.seek((seconds * 1_000_000.0) as i64).await }));
+        engine.register_fn("seek", |x: &mut ScriptPlayer, seconds: INT| x.call(|h| async move { h.seek(seconds * 1_000_000).await }));
+        engine.register_fn("volume", |x: &mut ScriptPlayer| -> Result<f64, Box<EvalAltResult>> {
+            x.call(|h| async move { h.get_volume().await })
+        });
+        engine.register_fn("position", |x: &mut ScriptPlayer| -> Result<f64, Box<EvalAltResult>> {
+            x.call(|h| async move { h.run(|player| player.get_position()).await }).map(|x| x.as_secs_f64())
+        });
+    }
+
+    /// The limits every call runs under.
+    pub fn limits(&self) -> &ScriptLimits {
+        &self.limits
+    }
+
+    /// Compiles a script and sets up its state with its `init` function, if it has one.
+    pub fn add_script(&mut self, name: &str, source: &str) -> Result<(), ScriptError> {
+        let compile_error = |message: String| ScriptError::Compile { script: name.to_string(), message };
+        let ast = self.engine.compile(source).map_err(|e| compile_error(e.to_string()))?;
+        if !ast.iter_functions().any(|x| x.name == HOOK && x.params.len() == 2) {
+            return Err(compile_error(format!("missing fn {}(event, player)", HOOK)));
+        }
+        let mut script = Script { name: name.to_string(), ast, state: Dynamic::from_map(Map::new()) };
+        if script.ast.iter_functions().any(|x| x.name == INIT && x.params.is_empty()) {
+            script.state = self.call(&mut script, INIT, ())?;
+        }
+        self.scripts.push(script);
+        return Ok(());
+    }
+
+    /// Reads a script from a file, named after the file.
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<(), ScriptError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)?;
+        let name = path.file_stem().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        return self.add_script(&name, &source);
+    }
+
+    /// Passes an event to every script, along with the player it came from. Blocks while the
+    /// scripts run. Returns what went wrong, a failing script does not stop the others.
+    pub fn handle_event(&mut self, event: &MergedEvent) -> Vec<ScriptError> {
+        let handle = match event {
+            MergedEvent::Appeared(handle) | MergedEvent::Event(handle, _) => handle.clone(),
+        };
+        let event = match event_map(event) {
+            Some(x) => x,
+            None => return vec![],
+        };
+        let player = ScriptPlayer { handle, deadline: self.deadline.clone() };
+        let mut errors = vec![];
+        let mut scripts = std::mem::take(&mut self.scripts);
+        for script in &mut scripts {
+            if let Err(e) = self.call(script, HOOK, (event.clone(), player.clone())) {
+                errors.push(e);
+            }
+        }
+        self.scripts = scripts;
+        return errors;
+    }
+
+    /// Calls `function` of `script` with `this` bound to its state, within the budget.
+    fn call(&self, script: &mut Script, function: &str, args: impl rhai::FuncArgs) -> Result<Dynamic, ScriptError> {
+        *self.deadline.lock().unwrap() = Some(Instant::now() + self.limits.time_budget);
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut script.state);
+        let result = self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &script.ast, function, args);
+        *self.deadline.lock().unwrap() = None;
+        let printed = std::mem::take(&mut *self.printed.lock().unwrap());
+        for text in printed {
+            (self.output)(&script.name, &text);
+        }
+        return result.map_err(|e| match out_of_budget(&e) {
+            true => ScriptError::BudgetExceeded { script: script.name.clone() },
+            false => ScriptError::Runtime { script: script.name.clone(), message: e.to_string() },
+        });
+    }
+
+    /// Runs the scripts against every player on `bus` until the connection to DBus is lost for
+    /// good. Players are picked up as they appear, checking every `retry_delay` milliseconds.
+    /// Errors are passed to `on_error`.
+    pub async fn run<F>(mut self, bus: BusConfig, retry_delay: u64, mut on_error: F)
+    where
+        F: FnMut(ScriptError) + Send + 'static,
+    {
+        let mut events = MergedEventsStream::with_bus(retry_delay, bus);
+        while let Some(event) = events.next().await {
+            // Scripts block on control calls, keep them off the executor
+            (self, on_error) = task::spawn_blocking(move || {
+                for error in self.handle_event(&event) {
+                    on_error(error);
+                }
+                (self, on_error)
+            }).await;
+        }
+    }
+}
+
+impl fmt::Debug for ScriptHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self.scripts.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+        f.debug_struct("ScriptHost").field("scripts", &names).field("limits", &self.limits).finish()
+    }
+}
+
+/// Whether the script was stopped for running out of time or operations, possibly deep inside
+/// functions it called.
+fn out_of_budget(error: &EvalAltResult) -> bool {
+    match error {
+        EvalAltResult::ErrorTerminated(..) | EvalAltResult::ErrorTooManyOperations(..) => true,
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _) => out_of_budget(inner),
+        _ => false,
+    }
+}
+
+/// The event as a map for scripts. `kind` names the event, the other fields depend on it.
+fn event_map(event: &MergedEvent) -> Option<Map> {
+    let mut map = Map::new();
+    let mut set = |key: &str, value: Dynamic| {
+        map.insert(key.into(), value);
+    };
+    let kind = match event {
+        MergedEvent::Appeared(_) => "appeared",
+        MergedEvent::Event(_, PlayerEvent::Reconnected) => "reconnected",
+        MergedEvent::Event(_, PlayerEvent::Root(_)) => return None,
+        MergedEvent::Event(_, PlayerEvent::Player(event)) => match event {
+            Event::PlayerShutDown => "player_quit",
+            Event::Playing => "playing",
+            Event::Paused => "paused",
+            Event::Stopped => "stopped",
+            Event::TrackChanged(metadata) => {
+                set_metadata(&mut set, metadata);
+                "track_changed"
+            },
+            Event::Seeked { position_in_us } => {
+                set("position", Dynamic::from_float(*position_in_us as f64 / 1_000_000.0));
+                "seeked"
+            },
+            Event::VolumeChanged(volume) => {
+                set("volume", Dynamic::from_float(*volume));
+                "volume_changed"
+            },
+            Event::PlaybackRateChanged(rate) => {
+                set("rate", Dynamic::from_float(*rate));
+                "rate_changed"
+            },
+            Event::ShuffleToggled(shuffle) => {
+                set("shuffle", Dynamic::from_bool(*shuffle));
+                "shuffle_toggled"
+            },
+            Event::LoopingChanged(status) => {
+                set("loop_status", Dynamic::from(format!("{:?}", status).to_lowercase()));
+                "looping_changed"
+            },
+            _ => return None,
+        },
+    };
+    set("kind", Dynamic::from(kind.to_string()));
+    return Some(map);
+}
+
+fn set_metadata(set: &mut impl FnMut(&str, Dynamic), metadata: &Metadata) {
+    let text = |x: Option<&str>| x.map_or(Dynamic::UNIT, |x| Dynamic::from(x.to_string()));
+    set("title", text(metadata.title()));
+    set("album", text(metadata.album_name()));
+    set("url", text(metadata.url()));
+    set("track_id", metadata.track_id().map_or(Dynamic::UNIT, |x| Dynamic::from(x.to_string())));
+    set("length", metadata.length().map_or(Dynamic::UNIT, |x| Dynamic::from_float(x.as_secs_f64())));
+    let artists = metadata.artists().unwrap_or_default().iter().map(|x| Dynamic::from(x.to_string())).collect::<Vec<_>>();
+    set("artists", Dynamic::from_array(artists));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn appeared() -> MergedEvent {
+        MergedEvent::Appeared(PlayerHandle::from_parts("Test", "org.mpris.MediaPlayer2.test"))
+    }
+
+    /// What scripts printed, with the name of the script.
+    type Printed = Arc<Mutex<Vec<(String, String)>>>;
+
+    /// A host that collects what scripts print.
+    fn host() -> (ScriptHost, Printed) {
+        let output = Arc::new(Mutex::new(vec![]));
+        let mut host = ScriptHost::new(ScriptLimits::default());
+        let collected = output.clone();
+        host.on_output(move |script, text| collected.lock().unwrap().push((script.to_string(), text.to_string())));
+        return (host, output);
+    }
+
+    #[test]
+    fn endless_loops_run_out_of_budget() {
+        let (mut host, _) = host();
+        host.add_script("spin", "fn on_event(event, player) { loop {} }").unwrap();
+        let errors = host.handle_event(&appeared());
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0], ScriptError::BudgetExceeded { script } if script == "spin"));
+    }
+
+    #[test]
+    fn state_is_kept_between_calls() {
+        let (mut host, output) = host();
+        let source = r#"
+            fn init() { #{ seen: 10 } }
+            fn on_event(event, player) {
+                this.seen += 1;
+                print(this.seen);
+            }
+        "#;
+        host.add_script("count", source).unwrap();
+        for _ in 0..3 {
+            assert!(host.handle_event(&appeared()).is_empty());
+        }
+        let printed = output.lock().unwrap().iter().map(|(_, text)| text.clone()).collect::<Vec<_>>();
+        assert_eq!(printed, vec!["11", "12", "13"]);
+    }
+
+    #[test]
+    fn scripts_need_the_hook() {
+        let (mut host, _) = host();
+        let missing = host.add_script("none", "fn init() { 1 }");
+        assert!(matches!(missing, Err(ScriptError::Compile { .. })));
+        let wrong_params = host.add_script("one", "fn on_event(event) { }");
+        assert!(matches!(wrong_params, Err(ScriptError::Compile { .. })));
+    }
+
+    #[test]
+    fn output_goes_to_the_handler() {
+        let (mut host, output) = host();
+        host.add_script("talk", r#"fn on_event(event, player) { print(event.kind + " " + player.identity); }"#).unwrap();
+        host.handle_event(&appeared());
+        assert_eq!(*output.lock().unwrap(), vec![("talk".to_string(), "appeared Test".to_string())]);
+    }
+
+    #[test]
+    fn imports_are_rejected() {
+        let (mut host, _) = host();
+        let source = r#"
+            import "/etc/anything" as other;
+            fn on_event(event, player) { }
+        "#;
+        assert!(matches!(host.add_script("sneaky", source), Err(ScriptError::Compile { .. })));
+        let nested = r#"fn on_event(event, player) { import "/etc/anything" as other; }"#;
+        assert!(matches!(host.add_script("nested", nested), Err(ScriptError::Compile { .. })));
+    }
+}
@@ -0,0 +1,363 @@
+//! Scrobbling built on [`ListenTracker`]. A [`Scrobbler`] sends [`ScrobbleRecord`]s to every
+//! [`ScrobbleSink`], and keeps scrobbles that could not be sent in an [`OfflineQueue`] on disk.
+//!
+//! Needs the `scrobble` feature. The Last.fm and ListenBrainz sinks in [`network`] need the
+//! `scrobble-network` feature.
+
+use std::{fmt, fs, io::{self, BufRead, Write}, path::PathBuf, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use async_std::stream::StreamExt;
+use mpris::Metadata;
+use serde::{Deserialize, Serialize};
+
+use crate::{fake_progress::ProgressClone, listen::{Listen, ListenEvent, ListenTracker}, progress::ProgressStream};
+
+#[cfg(feature = "scrobble-network")]
+pub mod network;
+
+/// Tracks shorter than this are never scrobbled.
+const MIN_TRACK_LENGTH: Duration = Duration::from_secs(30);
+
+/// Listening for this long is always enough to scrobble, even for long tracks.
+const MAX_REQUIRED_LISTEN: Duration = Duration::from_secs(4 * 60);
+
+/// A track as sent to scrobbling services.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrobbleTrack {
+    artist: String,
+    title: String,
+    album: Option<String>,
+    length: Option<Duration>,
+}
+
+impl ScrobbleTrack {
+    /// Takes the fields out of `metadata`. Returns [`None`] without an artist and title, which
+    /// every service requires.
+    pub fn from_metadata(metadata: &Metadata) -> Option<Self> {
+        let artists = metadata.artists()?;
+        if artists.is_empty() {
+            return None;
+        }
+        return Some(ScrobbleTrack {
+            artist: artists.join(", "),
+            title: metadata.title()?.to_string(),
+            album: metadata.album_name().map(|x| x.to_string()),
+            length: metadata.length(),
+        });
+    }
+
+    /// The artists of the track, joined by commas.
+    pub fn artist(&self) -> &str {
+        &self.artist
+    }
+
+    /// The title of the track.
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// The album of the track.
+    pub fn album(&self) -> Option<&str> {
+        self.album.as_deref()
+    }
+
+    /// The length of the track.
+    pub fn length(&self) -> Option<Duration> {
+        self.length
+    }
+}
+
+/// What a [`Scrobbler`] sends to its sinks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScrobbleRecord {
+    /// A track started playing.
+    NowPlaying(ScrobbleTrack),
+    /// A track was listened to long enough to count. `started_at` is in seconds since the Unix
+    /// epoch.
+    Scrobble { track: ScrobbleTrack, started_at: u64 },
+}
+
+/// Why a [`ScrobbleSink`] could not take a record.
+#[derive(Debug)]
+pub enum SinkError {
+    /// Worth trying again later, such as when the network is down. Scrobbles are kept in the
+    /// [`OfflineQueue`].
+    Temporary(String),
+    /// The service rejected the record, it is dropped.
+    Permanent(String),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::Temporary(e) => write!(f, "Scrobble could not be sent, will retry: {}", e),
+            SinkError::Permanent(e) => write!(f, "Scrobble was rejected: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SinkError {}
+
+/// Somewhere scrobbles go, such as a scrobbling service.
+pub trait ScrobbleSink: Send {
+    /// Sends a record. Called from a blocking thread.
+    fn submit(&mut self, record: &ScrobbleRecord) -> Result<(), SinkError>;
+}
+
+/// Scrobbles that could not be sent yet, stored as one JSON record per line.
+#[derive(Debug, Clone)]
+pub struct OfflineQueue {
+    path: PathBuf,
+}
+
+impl OfflineQueue {
+    /// Uses the file at `path`, which is created once something is queued.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        OfflineQueue { path: path.into() }
+    }
+
+    /// Adds a record to the end of the queue.
+    pub fn push(&self, record: &ScrobbleRecord) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let line = serde_json::to_string(record).map_err(io::Error::other)?;
+        return writeln!(file, "{}", line);
+    }
+
+    /// Every record in the queue, oldest first. Lines that can't be read are skipped.
+    pub fn records(&self) -> io::Result<Vec<ScrobbleRecord>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut records = vec![];
+        for line in io::BufReader::new(file).lines() {
+            if let Ok(record) = serde_json::from_str(&line?) {
+                records.push(record);
+            }
+        }
+        return Ok(records);
+    }
+
+    /// Sends the queued records to `sink`, oldest first, stopping at the first temporary error.
+    /// Sent and rejected records are removed from the queue.
+    pub fn flush(&self, sink: &mut dyn ScrobbleSink) -> io::Result<()> {
+        let records = self.records()?;
+        let mut sent = 0;
+        for record in &records {
+            match sink.submit(record) {
+                Ok(_) | Err(SinkError::Permanent(_)) => sent += 1,
+                Err(SinkError::Temporary(_)) => break,
+            }
+        }
+        if sent == 0 {
+            return Ok(());
+        }
+        let mut file = fs::File::create(&self.path)?;
+        for record in &records[sent..] {
+            let line = serde_json::to_string(record).map_err(io::Error::other)?;
+            writeln!(file, "{}", line)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Whether `listen` counts as a scrobble: the track is longer than 30 seconds and was listened
+/// to for half its length or 4 minutes, whichever comes first.
+pub fn should_scrobble(listen: &Listen) -> bool {
+    let length = match listen.length() {
+        Some(x) => x,
+        None => return false,
+    };
+    if length <= MIN_TRACK_LENGTH {
+        return false;
+    }
+    return listen.listened() >= (length / 2).min(MAX_REQUIRED_LISTEN);
+}
+
+/// Turns the progress of a player into [`ScrobbleRecord`]s. A scrobble that a sink can't take
+/// right now is put in the sink's [`OfflineQueue`], if it has one, and sent before the next
+/// record.
+#[derive(Default)]
+pub struct Scrobbler {
+    listens: ListenTracker,
+    sinks: Vec<(Box<dyn ScrobbleSink>, Option<OfflineQueue>)>,
+}
+
+impl Scrobbler {
+    /// Creates a new [`Scrobbler`] without any sinks.
+    pub fn new() -> Self {
+        Scrobbler::default()
+    }
+
+    /// Adds a sink, with its own queue for scrobbles it could not take.
+    pub fn add_sink(&mut self, sink: Box<dyn ScrobbleSink>, queue: Option<OfflineQueue>) {
+        self.sinks.push((sink, queue));
+    }
+
+    /// Feeds every item of `progress` to [`observe`](Self::observe) until the player quits.
+    pub async fn run(mut self, mut progress: ProgressStream) {
+        while let Some(x) = progress.next().await {
+            self = async_std::task::spawn_blocking(move || {
+                self.observe(&x);
+                self
+            }).await;
+        }
+        async_std::task::spawn_blocking(move || self.finish()).await;
+    }
+
+    /// Looks at a new progress of the player and sends records for what changed. Blocks while
+    /// the sinks are sending.
+    pub fn observe(&mut self, progress: &ProgressClone) {
+        for event in self.listens.observe(progress) {
+            let record = match event {
+                ListenEvent::Started(metadata) => ScrobbleTrack::from_metadata(&metadata).map(ScrobbleRecord::NowPlaying),
+                ListenEvent::Ended(listen) => Scrobbler::scrobble(&listen),
+            };
+            if let Some(record) = record {
+                self.send(&record);
+            }
+        }
+    }
+
+    /// Ends the current listen and scrobbles it if it counts. Call this when the player quits.
+    pub fn finish(&mut self) {
+        if let Some(record) = self.listens.finish().and_then(|x| Scrobbler::scrobble(&x)) {
+            self.send(&record);
+        }
+    }
+
+    fn scrobble(listen: &Listen) -> Option<ScrobbleRecord> {
+        if !should_scrobble(listen) {
+            return None;
+        }
+        let started_at = unix_time(listen.started_at());
+        let track = ScrobbleTrack::from_metadata(listen.metadata())?;
+        return Some(ScrobbleRecord::Scrobble { track, started_at });
+    }
+
+    fn send(&mut self, record: &ScrobbleRecord) {
+        for (sink, queue) in &mut self.sinks {
+            if let Some(queue) = queue.as_ref() {
+                let _ = queue.flush(sink.as_mut());
+            }
+            // Now playing is stale by the time it could be retried
+            if let (Err(SinkError::Temporary(_)), Some(queue), ScrobbleRecord::Scrobble { .. }) = (sink.submit(record), queue.as_ref(), record) {
+                let _ = queue.push(record);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Scrobbler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scrobbler").field("listens", &self.listens).field("sinks", &self.sinks.len()).finish()
+    }
+}
+
+/// Seconds since the Unix epoch, used for `started_at`.
+pub(crate) fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, process};
+
+    use mpris::MetadataValue;
+
+    use super::*;
+
+    fn listen(length: Option<u64>, listened: u64) -> Listen {
+        let mut fields = HashMap::new();
+        fields.insert("xesam:title".to_string(), MetadataValue::String("Title".to_string()));
+        fields.insert("xesam:artist".to_string(), MetadataValue::Array(vec![MetadataValue::String("Artist".to_string())]));
+        if let Some(length) = length {
+            fields.insert("mpris:length".to_string(), MetadataValue::I64(length as i64 * 1_000_000));
+        }
+        return Listen::from_parts(Metadata::from(fields), UNIX_EPOCH, Duration::from_secs(listened), Duration::from_secs(listened));
+    }
+
+    fn record(title: &str) -> ScrobbleRecord {
+        let track = ScrobbleTrack { artist: "Artist".to_string(), title: title.to_string(), album: None, length: None };
+        return ScrobbleRecord::Scrobble { track, started_at: 0 };
+    }
+
+    /// A queue in a file of its own, removed when dropped.
+    struct TempQueue(OfflineQueue);
+
+    impl TempQueue {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("mpris-async-{}-{}.jsonl", name, process::id()));
+            let _ = fs::remove_file(&path);
+            TempQueue(OfflineQueue::new(path))
+        }
+    }
+
+    impl Drop for TempQueue {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0.path);
+        }
+    }
+
+    /// Answers with `results` in order, then accepts everything.
+    struct FakeSink {
+        results: Vec<Result<(), SinkError>>,
+        submitted: Vec<ScrobbleRecord>,
+    }
+
+    impl ScrobbleSink for FakeSink {
+        fn submit(&mut self, record: &ScrobbleRecord) -> Result<(), SinkError> {
+            self.submitted.push(record.clone());
+            if self.results.is_empty() {
+                return Ok(());
+            }
+            return self.results.remove(0);
+        }
+    }
+
+    #[test]
+    fn half_the_track_is_enough() {
+        assert!(!should_scrobble(&listen(Some(100), 49)));
+        assert!(should_scrobble(&listen(Some(100), 50)));
+    }
+
+    #[test]
+    fn four_minutes_is_enough() {
+        assert!(!should_scrobble(&listen(Some(3600), 239)));
+        assert!(should_scrobble(&listen(Some(3600), 240)));
+    }
+
+    #[test]
+    fn short_tracks_are_never_scrobbled() {
+        assert!(!should_scrobble(&listen(Some(30), 30)));
+        assert!(should_scrobble(&listen(Some(31), 31)));
+        assert!(!should_scrobble(&listen(None, 600)));
+    }
+
+    #[test]
+    fn queue_keeps_records_the_sink_could_not_take() {
+        let queue = TempQueue::new("queue-keeps");
+        queue.0.push(&record("One")).unwrap();
+        queue.0.push(&record("Two")).unwrap();
+        let mut sink = FakeSink { results: vec![Err(SinkError::Temporary("offline".to_string()))], submitted: vec![] };
+        queue.0.flush(&mut sink).unwrap();
+        assert_eq!(sink.submitted, vec![record("One")]);
+        assert_eq!(queue.0.records().unwrap(), vec![record("One"), record("Two")]);
+
+        let mut sink = FakeSink { results: vec![Ok(()), Err(SinkError::Temporary("offline".to_string()))], submitted: vec![] };
+        queue.0.flush(&mut sink).unwrap();
+        assert_eq!(queue.0.records().unwrap(), vec![record("Two")]);
+    }
+
+    #[test]
+    fn queue_empties_once_sent() {
+        let queue = TempQueue::new("queue-empties");
+        queue.0.push(&record("One")).unwrap();
+        queue.0.push(&record("Two")).unwrap();
+        let mut sink = FakeSink { results: vec![Err(SinkError::Permanent("rejected".to_string()))], submitted: vec![] };
+        queue.0.flush(&mut sink).unwrap();
+        assert_eq!(sink.submitted, vec![record("One"), record("Two")]);
+        assert!(queue.0.records().unwrap().is_empty());
+    }
+}
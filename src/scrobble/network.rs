@@ -0,0 +1,300 @@
+//! Sinks for the Last.fm and ListenBrainz web services. Both take the endpoint they talk to, so
+//! they can be pointed at a compatible service or a local mock server.
+
+use std::collections::BTreeMap;
+
+use serde_json::json;
+
+use super::{ScrobbleRecord, ScrobbleSink, ScrobbleTrack, SinkError};
+
+/// The Last.fm API endpoint.
+pub const LAST_FM_ENDPOINT: &str = "https://ws.audioscrobbler.com/2.0/";
+
+/// The ListenBrainz endpoint for submitting listens.
+pub const LISTENBRAINZ_ENDPOINT: &str = "https://api.listenbrainz.org/1/submit-listens";
+
+/// Converts the result of a request. Server errors and rate limiting are worth retrying, other
+/// errors mean the record was rejected.
+fn check_response(response: Result<ureq::Response, ureq::Error>) -> Result<(), SinkError> {
+    match response {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(code, response)) if code == 429 || code >= 500 => {
+            Err(SinkError::Temporary(format!("{} {}", code, response.status_text())))
+        },
+        Err(ureq::Error::Status(code, response)) => {
+            Err(SinkError::Permanent(format!("{} {}", code, response.into_string().unwrap_or_default())))
+        },
+        Err(ureq::Error::Transport(e)) => Err(SinkError::Temporary(e.to_string())),
+    }
+}
+
+/// Scrobbles to Last.fm, or a service with the same API such as Libre.fm.
+///
+/// Needs an API key and secret from Last.fm, and a session key obtained through one of their
+/// authentication flows.
+#[derive(Debug, Clone)]
+pub struct LastFmSink {
+    api_key: String,
+    secret: String,
+    session_key: String,
+    endpoint: String,
+}
+
+impl LastFmSink {
+    /// Creates a sink that talks to Last.fm.
+    pub fn new(api_key: &str, secret: &str, session_key: &str) -> Self {
+        return LastFmSink::with_endpoint(api_key, secret, session_key, LAST_FM_ENDPOINT);
+    }
+
+    /// Creates a sink that talks to `endpoint` instead of Last.fm.
+    pub fn with_endpoint(api_key: &str, secret: &str, session_key: &str, endpoint: &str) -> Self {
+        LastFmSink {
+            api_key: api_key.to_string(),
+            secret: secret.to_string(),
+            session_key: session_key.to_string(),
+            endpoint: endpoint.to_string(),
+        }
+    }
+
+    fn track_params(track: &ScrobbleTrack, params: &mut BTreeMap<&'static str, String>) {
+        params.insert("artist", track.artist().to_string());
+        params.insert("track", track.title().to_string());
+        if let Some(album) = track.album() {
+            params.insert("album", album.to_string());
+        }
+        if let Some(length) = track.length() {
+            params.insert("duration", length.as_secs().to_string());
+        }
+    }
+
+    /// Signs the parameters as described in the Last.fm API documentation: every parameter
+    /// sorted by name and concatenated, followed by the secret, hashed with MD5.
+    fn sign(&self, params: &BTreeMap<&'static str, String>) -> String {
+        let mut text = String::new();
+        for (key, value) in params {
+            text.push_str(key);
+            text.push_str(value);
+        }
+        text.push_str(&self.secret);
+        return format!("{:x}", md5::compute(text));
+    }
+}
+
+impl ScrobbleSink for LastFmSink {
+    fn submit(&mut self, record: &ScrobbleRecord) -> Result<(), SinkError> {
+        let mut params = BTreeMap::new();
+        match record {
+            ScrobbleRecord::NowPlaying(track) => {
+                params.insert("method", "track.updateNowPlaying".to_string());
+                LastFmSink::track_params(track, &mut params);
+            },
+            ScrobbleRecord::Scrobble { track, started_at } => {
+                params.insert("method", "track.scrobble".to_string());
+                params.insert("timestamp", started_at.to_string());
+                LastFmSink::track_params(track, &mut params);
+            },
+        }
+        params.insert("api_key", self.api_key.clone());
+        params.insert("sk", self.session_key.clone());
+        let signature = self.sign(&params);
+        params.insert("api_sig", signature);
+        // The format is not part of the signature
+        params.insert("format", "json".to_string());
+
+        let form = params.iter().map(|(key, value)| (*key, value.as_str())).collect::<Vec<(&str, &str)>>();
+        return check_response(ureq::post(&self.endpoint).send_form(&form));
+    }
+}
+
+/// Submits listens to ListenBrainz, or a compatible service.
+#[derive(Debug, Clone)]
+pub struct ListenBrainzSink {
+    token: String,
+    endpoint: String,
+}
+
+impl ListenBrainzSink {
+    /// Creates a sink that talks to ListenBrainz, using the user token from their settings page.
+    pub fn new(token: &str) -> Self {
+        return ListenBrainzSink::with_endpoint(token, LISTENBRAINZ_ENDPOINT);
+    }
+
+    /// Creates a sink that talks to `endpoint` instead of ListenBrainz.
+    pub fn with_endpoint(token: &str, endpoint: &str) -> Self {
+        ListenBrainzSink { token: token.to_string(), endpoint: endpoint.to_string() }
+    }
+
+    fn track_metadata(track: &ScrobbleTrack) -> serde_json::Value {
+        let mut metadata = json!({
+            "artist_name": track.artist(),
+            "track_name": track.title(),
+        });
+        if let Some(album) = track.album() {
+            metadata["release_name"] = json!(album);
+        }
+        if let Some(length) = track.length() {
+            metadata["additional_info"] = json!({ "duration_ms": length.as_millis() as u64 });
+        }
+        return metadata;
+    }
+}
+
+impl ScrobbleSink for ListenBrainzSink {
+    fn submit(&mut self, record: &ScrobbleRecord) -> Result<(), SinkError> {
+        let body = match record {
+            ScrobbleRecord::NowPlaying(track) => json!({
+                "listen_type": "playing_now",
+                "payload": [{ "track_metadata": ListenBrainzSink::track_metadata(track) }],
+            }),
+            ScrobbleRecord::Scrobble { track, started_at } => json!({
+                "listen_type": "single",
+                "payload": [{ "listened_at": started_at, "track_metadata": ListenBrainzSink::track_metadata(track) }],
+            }),
+        };
+        let request = ureq::post(&self.endpoint).set("Authorization", &format!("Token {}", self.token));
+        return check_response(request.send_json(body));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::{BufRead, BufReader, Read, Write}, net::TcpListener, thread::{self, JoinHandle}, time::Duration};
+
+    use super::*;
+
+    /// A request as received by [`mock_server`].
+    struct Request {
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Request {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// Answers a single request with `status` on a local port. Returns the endpoint to use and
+    /// the request once it has been answered.
+    fn mock_server(status: u16) -> (String, JoinHandle<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/submit", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut headers = vec![];
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(": ") {
+                    Some((key, value)) => headers.push((key.to_string(), value.to_string())),
+                    None => break,
+                }
+            }
+            let request = Request { headers, body: String::new() };
+            let length = request.header("Content-Length").map_or(0, |x| x.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(stream, "HTTP/1.1 {} Mock\r\nContent-Length: 4\r\nConnection: close\r\n\r\nmock", status).unwrap();
+            return Request { body: String::from_utf8(body).unwrap(), ..request };
+        });
+        return (endpoint, server);
+    }
+
+    fn track() -> ScrobbleTrack {
+        ScrobbleTrack {
+            artist: "Some Artist".to_string(),
+            title: "Song & Dance".to_string(),
+            album: Some("Album".to_string()),
+            length: Some(Duration::from_secs(200)),
+        }
+    }
+
+    /// Decodes an `application/x-www-form-urlencoded` body.
+    fn parse_form(body: &str) -> BTreeMap<String, String> {
+        let decode = |text: &str| {
+            let bytes = text.as_bytes();
+            let mut decoded = vec![];
+            let mut i = 0;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'+' => decoded.push(b' '),
+                    b'%' => {
+                        decoded.push(u8::from_str_radix(&text[i + 1..i + 3], 16).unwrap());
+                        i += 2;
+                    },
+                    x => decoded.push(x),
+                }
+                i += 1;
+            }
+            String::from_utf8(decoded).unwrap()
+        };
+        return body.split('&').filter_map(|x| x.split_once('=')).map(|(key, value)| (decode(key), decode(value))).collect();
+    }
+
+    #[test]
+    fn last_fm_scrobble_is_signed() {
+        let (endpoint, server) = mock_server(200);
+        let mut sink = LastFmSink::with_endpoint("key", "secret", "session", &endpoint);
+        sink.submit(&ScrobbleRecord::Scrobble { track: track(), started_at: 1_700_000_000 }).unwrap();
+        let request = server.join().unwrap();
+
+        assert_eq!(request.header("Content-Type"), Some("application/x-www-form-urlencoded"));
+        let form = parse_form(&request.body);
+        // Every parameter but format and the signature, sorted by name
+        let signed = "albumAlbumapi_keykeyartistSome Artistduration200methodtrack.scrobblesksessiontimestamp1700000000trackSong & Dancesecret";
+        let expected = [
+            ("album", "Album"),
+            ("api_key", "key"),
+            ("api_sig", &format!("{:x}", md5::compute(signed))),
+            ("artist", "Some Artist"),
+            ("duration", "200"),
+            ("format", "json"),
+            ("method", "track.scrobble"),
+            ("sk", "session"),
+            ("timestamp", "1700000000"),
+            ("track", "Song & Dance"),
+        ].into_iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<BTreeMap<_, _>>();
+        assert_eq!(form, expected);
+    }
+
+    #[test]
+    fn listenbrainz_listen_is_sent_as_json() {
+        let (endpoint, server) = mock_server(200);
+        let mut sink = ListenBrainzSink::with_endpoint("token", &endpoint);
+        sink.submit(&ScrobbleRecord::Scrobble { track: track(), started_at: 1_700_000_000 }).unwrap();
+        let request = server.join().unwrap();
+
+        assert_eq!(request.header("Authorization"), Some("Token token"));
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body, json!({
+            "listen_type": "single",
+            "payload": [{
+                "listened_at": 1_700_000_000,
+                "track_metadata": {
+                    "artist_name": "Some Artist",
+                    "track_name": "Song & Dance",
+                    "release_name": "Album",
+                    "additional_info": { "duration_ms": 200_000 },
+                },
+            }],
+        }));
+    }
+
+    #[test]
+    fn server_errors_are_temporary() {
+        let (endpoint, server) = mock_server(503);
+        let mut sink = ListenBrainzSink::with_endpoint("token", &endpoint);
+        let result = sink.submit(&ScrobbleRecord::NowPlaying(track()));
+        server.join().unwrap();
+        assert!(matches!(result, Err(SinkError::Temporary(_))));
+
+        let (endpoint, server) = mock_server(400);
+        let mut sink = LastFmSink::with_endpoint("key", "secret", "session", &endpoint);
+        let result = sink.submit(&ScrobbleRecord::NowPlaying(track()));
+        server.join().unwrap();
+        assert!(matches!(result, Err(SinkError::Permanent(_))));
+    }
+}
@@ -0,0 +1,258 @@
+//! [`pause_all`] pauses every playing player and returns a [`SessionSnapshot`] that [`restore`]
+//! uses to resume exactly those players later, possibly from another process.
+//!
+//! Needs the `session` feature.
+
+use std::{fmt, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use mpris::PlaybackStatus;
+use serde::{Deserialize, Serialize};
+
+use crate::{connection::BusConfig, error::Error, handle::PlayerHandle, quirks::{quirks_for, NO_TRACK}};
+
+/// How far the position may have moved while paused before [`restore`] moves it back.
+const POSITION_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// A player that was playing when the snapshot was taken.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerSnapshot {
+    identity: String,
+    bus_name: String,
+    unique_name: String,
+    track_id: Option<String>,
+    url: Option<String>,
+    position: Duration,
+    volume: Option<f64>,
+}
+
+impl PlayerSnapshot {
+    /// The identity of the player.
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// The bus name of the player, which tells apart several instances of one player.
+    pub fn bus_name(&self) -> &str {
+        &self.bus_name
+    }
+
+    /// The unique bus name of the player, such as `:1.42`. A player that quit and started again
+    /// gets a new one, even if its bus name is the same.
+    pub fn unique_name(&self) -> &str {
+        &self.unique_name
+    }
+
+    /// The track id of the track that was playing.
+    pub fn track_id(&self) -> Option<&str> {
+        self.track_id.as_deref()
+    }
+
+    /// The url of the track that was playing.
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// Where the track was paused.
+    pub fn position(&self) -> Duration {
+        self.position
+    }
+
+    /// The volume of the player in the 0.0-1.0 range, if it has one.
+    pub fn volume(&self) -> Option<f64> {
+        self.volume
+    }
+}
+
+/// The players [`pause_all`] paused. Serializes with serde, see [`to_json`](Self::to_json).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    /// Seconds since the Unix epoch.
+    taken_at: u64,
+    players: Vec<PlayerSnapshot>,
+}
+
+impl SessionSnapshot {
+    /// When the snapshot was taken.
+    pub fn taken_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.taken_at)
+    }
+
+    /// The players that were paused.
+    pub fn players(&self) -> &[PlayerSnapshot] {
+        &self.players
+    }
+
+    /// Whether no player was playing.
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+
+    /// The snapshot as JSON, to hand to another process.
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    /// Reads a snapshot written by [`to_json`](Self::to_json).
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+/// What [`restore`] did with each player of a snapshot.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// Bus names of the players that were resumed.
+    pub resumed: Vec<String>,
+    /// Bus names of the players that are gone, or were playing again already.
+    pub skipped: Vec<String>,
+    /// Bus names of the players that could not be resumed.
+    pub failed: Vec<String>,
+}
+
+impl fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} resumed, {} skipped, {} failed", self.resumed.len(), self.skipped.len(), self.failed.len())
+    }
+}
+
+/// Pauses every player on `bus` that is playing, and returns what they were doing. Players that
+/// can't be paused are left out of the snapshot.
+pub async fn pause_all(bus: &BusConfig) -> Result<SessionSnapshot, Error> {
+    let mut players = vec![];
+    for handle in PlayerHandle::find_all(bus).await? {
+        let snapshot = match read_playing(&handle).await {
+            Ok(Some(x)) => x,
+            // Not playing, or gone since it was found
+            Ok(None) | Err(_) => continue,
+        };
+        if handle.pause().await.is_ok() {
+            players.push(snapshot);
+        }
+    }
+    let taken_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    return Ok(SessionSnapshot { taken_at, players });
+}
+
+/// Resumes the players in `snapshot` that are still on `bus` and still paused, putting their
+/// volume back and moving the track back to where it was if it moved. Players that have gone
+/// away since are skipped, including ones that quit and started again under the same name.
+pub async fn restore(snapshot: &SessionSnapshot, bus: &BusConfig) -> Result<RestoreReport, Error> {
+    let handles = PlayerHandle::find_all(bus).await?;
+    let mut report = RestoreReport::default();
+    for player in &snapshot.players {
+        let handle = match handles.iter().find(|x| x.bus_name() == player.bus_name) {
+            Some(x) => x,
+            None => {
+                report.skipped.push(player.bus_name.clone());
+                continue;
+            },
+        };
+        match restore_player(handle, player).await {
+            Ok(true) => report.resumed.push(player.bus_name.clone()),
+            Ok(false) => report.skipped.push(player.bus_name.clone()),
+            Err(_) => report.failed.push(player.bus_name.clone()),
+        }
+    }
+    return Ok(report);
+}
+
+/// Reads the state of the player if it is playing.
+async fn read_playing(handle: &PlayerHandle) -> Result<Option<PlayerSnapshot>, Error> {
+    let identity = handle.identity().to_string();
+    let bus_name = handle.bus_name().to_string();
+    return handle.run(move |player| {
+        if player.get_playback_status()? != PlaybackStatus::Playing {
+            return Ok::<_, Error>(None);
+        }
+        let metadata = player.get_metadata()?;
+        let volume = player.checked_get_volume()?.map(|x| quirks_for(player).normalize_volume(x));
+        Ok(Some(PlayerSnapshot {
+            identity,
+            bus_name,
+            unique_name: player.unique_name().to_string(),
+            track_id: metadata.track_id().map(|x| x.to_string()),
+            url: metadata.url().map(|x| x.to_string()),
+            position: player.get_position()?,
+            volume,
+        }))
+    }).await;
+}
+
+/// Resumes one player. Returns false if it was skipped.
+async fn restore_player(handle: &PlayerHandle, snapshot: &PlayerSnapshot) -> Result<bool, Error> {
+    let (unique_name, status, track_id, url, position) = handle.run(|player| {
+        let metadata = player.get_metadata()?;
+        Ok::<_, Error>((player.unique_name().to_string(), player.get_playback_status()?, metadata.track_id(), metadata.url().map(|x| x.to_string()), player.get_position()?))
+    }).await?;
+    // A new instance of the player has nothing to resume
+    if unique_name != snapshot.unique_name {
+        return Ok(false);
+    }
+    // Someone else started it again in the meantime
+    if status == PlaybackStatus::Playing {
+        return Ok(false);
+    }
+
+    let same_track = track_id.as_ref().map(|x| x.to_string()) == snapshot.track_id && url == snapshot.url;
+    let moved = position.max(snapshot.position) - position.min(snapshot.position) > POSITION_TOLERANCE;
+    if same_track && moved {
+        match track_id {
+            Some(id) if id.as_str() != NO_TRACK => handle.set_position(&id, snapshot.position).await?,
+            _ => handle.seek(snapshot.position.as_micros() as i64 - position.as_micros() as i64).await?,
+        }
+    }
+    if let Some(volume) = snapshot.volume {
+        let _ = handle.set_volume(volume).await;
+    }
+    handle.play().await?;
+    return Ok(true);
+}
+
+impl PlayerHandle {
+    /// Resumes a player from `snapshot`, see [`restore`]. Returns false if the player was
+    /// already playing, or is not the instance the snapshot was taken of.
+    pub async fn restore(&self, snapshot: &PlayerSnapshot) -> Result<bool, Error> {
+        restore_player(self, snapshot).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_round_trip_through_json() {
+        let snapshot = SessionSnapshot {
+            taken_at: 1_700_000_000,
+            players: vec![
+                PlayerSnapshot {
+                    identity: "mpv".to_string(),
+                    bus_name: "org.mpris.MediaPlayer2.mpv".to_string(),
+                    unique_name: ":1.42".to_string(),
+                    track_id: Some("/org/mpris/MediaPlayer2/Track/1".to_string()),
+                    url: Some("file:///music/track.flac".to_string()),
+                    position: Duration::from_millis(83_250),
+                    volume: Some(0.5),
+                },
+                PlayerSnapshot {
+                    identity: "Spotify".to_string(),
+                    bus_name: "org.mpris.MediaPlayer2.spotify".to_string(),
+                    unique_name: ":1.7".to_string(),
+                    track_id: None,
+                    url: None,
+                    position: Duration::ZERO,
+                    volume: None,
+                },
+            ],
+        };
+        let read = SessionSnapshot::from_json(&snapshot.to_json().unwrap()).unwrap();
+        assert_eq!(read, snapshot);
+        assert_eq!(read.taken_at(), UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(read.players()[0].unique_name(), ":1.42");
+    }
+
+    #[test]
+    fn rejects_snapshots_without_players() {
+        assert!(SessionSnapshot::from_json(r#"{"taken_at": 0}"#).is_err());
+    }
+}
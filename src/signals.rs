@@ -0,0 +1,117 @@
+//! Blocking listener for raw DBus signals of a player. Used for the interfaces and properties
+//! that [`mpris::PlayerEvents`] does not report.
+
+use std::time::{Duration, Instant};
+
+use dbus::{arg::PropMap, ffidisp::{Connection, stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged}, message::{MessageType, SignalArgs}, Message};
+
+use crate::{error::Error, handle::{PlayerHandle, DBUS_TIMEOUT_MS, MPRIS_PATH}};
+
+/// How long to block waiting for messages before checking if the connection is still alive.
+const INCOMING_TIMEOUT_MS: u32 = 1000;
+
+/// A signal sent by the player.
+pub(crate) enum Signal {
+    /// Properties of `interface` changed.
+    PropertiesChanged {
+        interface: String,
+        changed: PropMap,
+    },
+    /// Any other signal emitted on the MPRIS object path.
+    Other(Message),
+    /// The player left the bus.
+    PlayerQuit,
+}
+
+/// Iterator that blocks until the player sends a [`Signal`]. It ends after
+/// [`Signal::PlayerQuit`] or when the connection is lost.
+pub(crate) struct SignalListener {
+    connection: Connection,
+    bus_name: String,
+    owner: String,
+    quit: bool,
+}
+
+impl SignalListener {
+    pub(crate) fn new(handle: &PlayerHandle) -> Result<Self, Error> {
+        let connection = handle.bus().connect().map_err(Error::Connection)?;
+        let bus_name = handle.bus_name().to_string();
+        // Signals are sent from the unique name of the player, not its well known name
+        let owner = SignalListener::name_owner(&connection, &bus_name).map_err(|e| Error::DBus(e.into()))?;
+        connection.add_match(&format!("type='signal',sender='{}',path='{}'", bus_name, MPRIS_PATH)).map_err(|e| Error::DBus(e.into()))?;
+        connection.add_match(&format!("type='signal',interface='org.freedesktop.DBus',member='NameOwnerChanged',arg0='{}'", bus_name)).map_err(|e| Error::DBus(e.into()))?;
+        return Ok(SignalListener { connection, bus_name, owner, quit: false });
+    }
+
+    fn name_owner(connection: &Connection, bus_name: &str) -> Result<String, dbus::Error> {
+        let message = Message::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "GetNameOwner")
+            .map_err(|e| dbus::Error::new_failed(&e))?
+            .append1(bus_name);
+        let reply = connection.send_with_reply_and_block(message, DBUS_TIMEOUT_MS)?;
+        return Ok(reply.read1::<&str>()?.to_string());
+    }
+
+    fn parse(&mut self, message: Message) -> Option<Signal> {
+        if message.msg_type() != MessageType::Signal {
+            return None;
+        }
+        if message.member().as_deref() == Some("NameOwnerChanged") {
+            let (name, _, new_owner) = message.read3::<&str, &str, &str>().ok()?;
+            if name == self.bus_name && new_owner.is_empty() {
+                self.quit = true;
+                return Some(Signal::PlayerQuit);
+            }
+            return None;
+        }
+        if message.sender().as_deref() != Some(self.owner.as_str()) {
+            return None;
+        }
+        if let Some(x) = PropertiesPropertiesChanged::from_message(&message) {
+            return Some(Signal::PropertiesChanged {
+                interface: x.interface_name,
+                changed: x.changed_properties,
+            });
+        }
+        return Some(Signal::Other(message));
+    }
+}
+
+impl SignalListener {
+    /// Waits up to `timeout_ms` for the next [`Signal`], a timeout of 0 only takes what already
+    /// arrived. Returns [`None`] when nothing arrived in time, check
+    /// [`is_closed`](Self::is_closed) to tell that apart from the listener ending.
+    pub(crate) fn next_within(&mut self, timeout_ms: u32) -> Option<Signal> {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms as u64);
+        while !self.is_closed() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.connection.incoming(left.as_millis() as u32).next() {
+                Some(message) => {
+                    if let Some(signal) = self.parse(message) {
+                        return Some(signal);
+                    }
+                },
+                None if left.is_zero() => return None,
+                None => {},
+            }
+        }
+        return None;
+    }
+
+    /// Whether the player has quit or the connection was lost.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.quit || !self.connection.is_connected()
+    }
+}
+
+impl Iterator for SignalListener {
+    type Item = Signal;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.is_closed() {
+            if let Some(signal) = self.next_within(INCOMING_TIMEOUT_MS) {
+                return Some(signal);
+            }
+        }
+        return None;
+    }
+}
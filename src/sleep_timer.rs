@@ -0,0 +1,291 @@
+//! [`SleepTimer`] pauses a player after some time or at the end of a track, optionally fading
+//! out first.
+
+use std::{sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use async_std::{channel::{unbounded, Receiver, Sender}, task, stream::Stream};
+use mpris::Player;
+
+use crate::{fade::{FadeCanceller, FadeCurve, FadeOutcome}, fake_progress::ProgressClone, handle::PlayerHandle, waker::{waking_channel, WakingReceiver, WakingSender}};
+
+/// How often the timer checks the player, in milliseconds. The player is paused at most this
+/// late.
+const TICK_INTERVAL_MS: u32 = 250;
+
+/// When the timer should pause the player.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepMode {
+    /// After the given time has passed, whether or not the player is playing.
+    After(Duration),
+    /// When the current track ends.
+    EndOfTrack,
+    /// When the current track and the given number of tracks after it have ended.
+    AfterTracks(u32),
+}
+
+/// Items of a [`SleepTimer`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SleepTimerEvent {
+    /// Time left until the player is paused. [`None`] if it can't be known yet, such as while
+    /// there are more tracks to go before the last one or when the track has no length.
+    Remaining(Option<Duration>),
+    /// The fade out has started.
+    FadingOut,
+    /// The player was paused. This is the last item.
+    Paused,
+    /// The timer was cancelled, the player quit, or the volume was changed during the fade
+    /// out. This is the last item.
+    Cancelled,
+}
+
+enum TimerCommand {
+    Extend(Duration),
+    ExtendTracks(u32),
+    Cancel,
+}
+
+/// Pauses a player according to a [`SleepMode`]. As a stream it reports [`SleepTimerEvent`]s,
+/// with the remaining time sent whenever it changes by a second. Makes a new thread that runs
+/// until the timer has fired or been cancelled. Clones control the same timer.
+#[derive(Debug, Clone)]
+pub struct SleepTimer {
+    commands: Sender<TimerCommand>,
+    mode: SleepMode,
+    fade: Arc<Mutex<Option<FadeCanceller>>>,
+    reciever: WakingReceiver<SleepTimerEvent>,
+}
+
+impl SleepTimer {
+    /// Starts a timer that pauses the player without fading out.
+    pub fn start(handle: &PlayerHandle, mode: SleepMode) -> Self {
+        return SleepTimer::spawn(handle, mode, None);
+    }
+
+    /// Starts a timer that fades the volume out over `duration` before pausing. The volume is
+    /// restored after pausing, see [`PlayerHandle::fade_out_and_pause`]. Cancelling or extending
+    /// the timer during the fade also restores it.
+    pub fn with_fade(handle: &PlayerHandle, mode: SleepMode, duration: Duration, curve: FadeCurve) -> Self {
+        return SleepTimer::spawn(handle, mode, Some((duration, curve)));
+    }
+
+    fn spawn(handle: &PlayerHandle, mode: SleepMode, fade: Option<(Duration, FadeCurve)>) -> Self {
+        let (commands, command_reciever) = unbounded();
+        let (sender, reciever) = waking_channel();
+        let timer = SleepTimer { commands, mode, fade: Arc::new(Mutex::new(None)), reciever };
+        let listener = TimerListener { handle: handle.clone(), commands: command_reciever, fade, fade_canceller: timer.fade.clone(), sender };
+        thread::spawn(move || listener.run(mode));
+        return timer;
+    }
+
+    /// Adds time to a timer started with [`SleepMode::After`]. Does nothing for the other modes.
+    pub fn extend(&self, by: Duration) {
+        let _ = self.commands.try_send(TimerCommand::Extend(by));
+        if let SleepMode::After(_) = self.mode {
+            self.stop_fade();
+        }
+    }
+
+    /// Adds tracks to a timer started with [`SleepMode::EndOfTrack`] or
+    /// [`SleepMode::AfterTracks`]. Does nothing for [`SleepMode::After`].
+    pub fn extend_tracks(&self, tracks: u32) {
+        let _ = self.commands.try_send(TimerCommand::ExtendTracks(tracks));
+        match self.mode {
+            SleepMode::After(_) => {},
+            _ => self.stop_fade(),
+        }
+    }
+
+    /// Stops the timer, including a fade out that is already running.
+    pub fn cancel(&self) {
+        let _ = self.commands.try_send(TimerCommand::Cancel);
+        self.stop_fade();
+    }
+
+    /// Stops a running fade out, the timer thread then reads the command that was just sent.
+    fn stop_fade(&self) {
+        if let Some(fade) = self.fade.lock().unwrap().as_ref() {
+            fade.cancel();
+        }
+    }
+
+    /// Access to the reciever used to send events around.
+    pub fn get_reciever(&self) -> Receiver<SleepTimerEvent> {
+        self.reciever.reciever()
+    }
+}
+
+/// What is left before the timer fires.
+enum Countdown {
+    Deadline(Instant),
+    /// Track ends left, counting the current track.
+    Tracks(u32),
+}
+
+/// What the commands read by [`TimerListener::apply_commands`] did.
+#[derive(PartialEq)]
+enum Applied {
+    Nothing,
+    Extended,
+    Cancelled,
+}
+
+struct TimerListener {
+    handle: PlayerHandle,
+    commands: Receiver<TimerCommand>,
+    fade: Option<(Duration, FadeCurve)>,
+    fade_canceller: Arc<Mutex<Option<FadeCanceller>>>,
+    sender: WakingSender<SleepTimerEvent>,
+}
+
+impl TimerListener {
+    fn run(self, mode: SleepMode) {
+        let event = match self.handle.find() {
+            Ok(player) => self.count_down(&player, mode),
+            Err(_) => SleepTimerEvent::Cancelled,
+        };
+        self.sender.send(event);
+        self.sender.close();
+    }
+
+    /// Runs until the timer fires or is cancelled, and returns the last event.
+    fn count_down(&self, player: &Player, mode: SleepMode) -> SleepTimerEvent {
+        let mut tracker = match player.track_progress(TICK_INTERVAL_MS) {
+            Ok(x) => x,
+            Err(_) => return SleepTimerEvent::Cancelled,
+        };
+        let mut countdown = match mode {
+            SleepMode::After(duration) => Countdown::Deadline(Instant::now() + duration),
+            SleepMode::EndOfTrack => Countdown::Tracks(1),
+            SleepMode::AfterTracks(tracks) => Countdown::Tracks(tracks.saturating_add(1)),
+        };
+        let mut track = ProgressClone::from(tracker.tick().progress).track_key();
+        let mut last_sent = None;
+
+        loop {
+            if self.apply_commands(&mut countdown) == Applied::Cancelled {
+                return SleepTimerEvent::Cancelled;
+            }
+
+            let tick = tracker.tick();
+            if tick.player_quit {
+                return SleepTimerEvent::Cancelled;
+            }
+            let progress = ProgressClone::from(tick.progress);
+
+            if let Countdown::Tracks(left) = &mut countdown {
+                let key = progress.track_key();
+                if key != track {
+                    track = key;
+                    *left -= 1;
+                    // The end of the last track was missed between two ticks
+                    if *left == 0 {
+                        return self.pause(player);
+                    }
+                }
+            }
+
+            let remaining = match countdown {
+                Countdown::Deadline(deadline) => Some(deadline.saturating_duration_since(Instant::now())),
+                Countdown::Tracks(1) => progress.time_left(),
+                Countdown::Tracks(_) => None,
+            };
+
+            if let Some(remaining) = remaining {
+                let tick_interval = Duration::from_millis(TICK_INTERVAL_MS as u64);
+                match self.fade {
+                    Some((duration, curve)) if remaining <= duration => {
+                        match self.fade_out(player, &mut countdown, remaining, curve) {
+                            Some(event) => return event,
+                            // Extended during the fade, carry on counting down
+                            None => continue,
+                        }
+                    },
+                    _ if remaining < tick_interval => {
+                        thread::sleep(remaining);
+                        return self.pause(player);
+                    },
+                    _ => {},
+                }
+            }
+
+            let rounded = remaining.map(|x| x.as_secs());
+            if last_sent != Some(rounded) {
+                last_sent = Some(rounded);
+                if !self.sender.send(SleepTimerEvent::Remaining(remaining)) {
+                    return SleepTimerEvent::Cancelled;
+                }
+            }
+        }
+    }
+
+    fn pause(&self, player: &Player) -> SleepTimerEvent {
+        match player.pause() {
+            Ok(_) => SleepTimerEvent::Paused,
+            Err(_) => SleepTimerEvent::Cancelled,
+        }
+    }
+
+    /// Reads every queued command into `countdown`. Extensions that don't apply to the mode
+    /// are dropped.
+    fn apply_commands(&self, countdown: &mut Countdown) -> Applied {
+        let mut applied = Applied::Nothing;
+        while let Ok(command) = self.commands.try_recv() {
+            match (command, &mut *countdown) {
+                (TimerCommand::Extend(by), Countdown::Deadline(deadline)) => {
+                    *deadline += by;
+                    applied = Applied::Extended;
+                },
+                (TimerCommand::ExtendTracks(tracks), Countdown::Tracks(left)) => {
+                    *left = left.saturating_add(tracks);
+                    applied = Applied::Extended;
+                },
+                (TimerCommand::Cancel, _) => return Applied::Cancelled,
+                _ => {},
+            }
+        }
+        return applied;
+    }
+
+    /// Fades out and pauses. Returns [`None`] if the timer was extended instead, with the volume
+    /// put back to what it was.
+    fn fade_out(&self, player: &Player, countdown: &mut Countdown, duration: Duration, curve: FadeCurve) -> Option<SleepTimerEvent> {
+        let original = player.get_volume().ok();
+        let fade = self.handle.fade_out_and_pause(duration, curve);
+        *self.fade_canceller.lock().unwrap() = Some(fade.canceller());
+        // Commands sent before the canceller was stored only reached the command channel
+        match self.apply_commands(countdown) {
+            Applied::Nothing => {},
+            Applied::Extended => {
+                *self.fade_canceller.lock().unwrap() = None;
+                return None;
+            },
+            Applied::Cancelled => return Some(SleepTimerEvent::Cancelled),
+        }
+        self.sender.send(SleepTimerEvent::FadingOut);
+        let outcome = task::block_on(fade);
+        *self.fade_canceller.lock().unwrap() = None;
+        match outcome {
+            Ok(FadeOutcome::Completed) => return Some(SleepTimerEvent::Paused),
+            Ok(FadeOutcome::Cancelled) => {},
+            // The volume was changed by someone else, leave it as they set it
+            _ => return Some(SleepTimerEvent::Cancelled),
+        }
+
+        // Stopped by a command, which should not leave the player faded down
+        if let Some(volume) = original {
+            let _ = player.set_volume(volume);
+        }
+        return match self.apply_commands(countdown) {
+            Applied::Extended => None,
+            _ => Some(SleepTimerEvent::Cancelled),
+        };
+    }
+}
+
+impl Stream for SleepTimer {
+    type Item = SleepTimerEvent;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
+        self.reciever.poll_recv(cx)
+    }
+}
@@ -0,0 +1,176 @@
+//! [`PositionTicker`] yields the interpolated position of a player at a fixed rate, for progress
+//! bars and synced lyrics.
+
+use std::{future::Future, pin::Pin, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex, Weak}, task::{Context, Poll}, thread, time::{Duration, Instant}};
+
+use async_std::{stream::Stream, task};
+use mpris::{PlaybackStatus, Player};
+
+use crate::{fake_progress::{DurationExtensions, ProgressClone}, handle::{PlayerHandle, PLAYER_INTERFACE}, signals::{Signal, SignalListener}, waker::WakerSlot};
+
+/// How long the listener waits for a signal before checking if the ticker was dropped.
+const LISTENER_TIMEOUT_MS: u32 = 1000;
+
+/// The properties that move the position anchor when they change.
+const ANCHOR_PROPERTIES: [&str; 3] = ["PlaybackStatus", "Rate", "Metadata"];
+
+/// Shared between the ticker and its listener thread.
+#[derive(Debug, Default)]
+struct TickerState {
+    anchor: Mutex<Option<ProgressClone>>,
+    /// Bumped every time the anchor is replaced, so the ticker can yield the new position
+    /// right away.
+    generation: AtomicUsize,
+    closed: AtomicBool,
+    waker: WakerSlot,
+}
+
+impl TickerState {
+    fn set_anchor(&self, anchor: ProgressClone) {
+        *self.anchor.lock().unwrap() = Some(anchor);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.waker.wake();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.waker.wake();
+    }
+}
+
+/// Stream of the position of a player, interpolated from the last known position, rate and
+/// playback status. Yields `ticks_per_second` times a second while playing and not at all while
+/// paused. DBus is only used when the player signals a change: a `Seeked` signal, or a change to
+/// the playback status, rate or track. A position is yielded right after each of those.
+///
+/// Makes a new thread to listen for those changes, which stops once the ticker is dropped or the
+/// player quits. The stream ends when the player quits.
+pub struct PositionTicker {
+    state: Arc<TickerState>,
+    period: Duration,
+    next_tick: Instant,
+    sleep: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    generation: usize,
+}
+
+impl PositionTicker {
+    /// Creates a new [`PositionTicker`] yielding `ticks_per_second` positions a second.
+    pub fn new(handle: &PlayerHandle, ticks_per_second: u32) -> Self {
+        let state = Arc::new(TickerState::default());
+        let weak = Arc::downgrade(&state);
+        let handle = handle.clone();
+        thread::spawn(move || PositionTicker::anchor_listener(&handle, weak));
+        let period = Duration::from_secs(1) / ticks_per_second.max(1);
+        return PositionTicker { state, period, next_tick: Instant::now(), sleep: None, generation: 0 };
+    }
+
+    fn anchor_listener(handle: &PlayerHandle, state: Weak<TickerState>) {
+        PositionTicker::track_anchor(handle, &state);
+        if let Some(state) = state.upgrade() {
+            state.close();
+        }
+    }
+
+    fn track_anchor(handle: &PlayerHandle, state: &Weak<TickerState>) {
+        // Subscribe before reading, so no change is missed in between
+        let mut signals = match SignalListener::new(handle) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let player = match handle.find() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        match state.upgrade() {
+            Some(state) if PositionTicker::reread(&player, &state) => {},
+            _ => return,
+        }
+
+        loop {
+            let signal = signals.next_within(LISTENER_TIMEOUT_MS);
+            let state = match state.upgrade() {
+                Some(x) => x,
+                None => return,
+            };
+            match signal {
+                // Rereads the anchor when it moved, and stops if that fails
+                Some(Signal::PropertiesChanged { interface, changed, .. }) if interface == PLAYER_INTERFACE && ANCHOR_PROPERTIES.iter().any(|x| changed.contains_key(*x)) && !PositionTicker::reread(&player, &state) => return,
+                Some(Signal::Other(message)) if message.interface().as_deref() == Some(PLAYER_INTERFACE) && message.member().as_deref() == Some("Seeked") => {
+                    // Seeked carries the new position, no need to ask for it
+                    let position = match message.read1::<i64>() {
+                        Ok(x) => Duration::from_micros_ext(x.max(0) as u64),
+                        Err(_) => continue,
+                    };
+                    let anchor = state.anchor.lock().unwrap().clone();
+                    if let Some(mut anchor) = anchor {
+                        anchor.position = position;
+                        anchor.instant = Instant::now();
+                        state.set_anchor(anchor);
+                    }
+                },
+                Some(Signal::PlayerQuit) => return,
+                None if signals.is_closed() => return,
+                _ => {},
+            }
+        }
+    }
+
+    /// Reads the anchor from the player. Returns false if it could not be read.
+    fn reread(player: &Player, state: &TickerState) -> bool {
+        return match ProgressClone::read(player) {
+            Ok(anchor) => {
+                state.set_anchor(anchor);
+                true
+            },
+            Err(_) => false,
+        };
+    }
+
+    fn restart_ticks(&mut self) {
+        self.next_tick = Instant::now() + self.period;
+        self.sleep = None;
+    }
+}
+
+impl std::fmt::Debug for PositionTicker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PositionTicker").field("state", &self.state).field("period", &self.period).field("generation", &self.generation).finish()
+    }
+}
+
+impl Stream for PositionTicker {
+    type Item = Duration;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Register before looking at the state, so a change in between still wakes us
+        self.state.waker.register(cx.waker());
+        if self.state.closed.load(Ordering::SeqCst) {
+            return Poll::Ready(None);
+        }
+        let (position, playing) = match self.state.anchor.lock().unwrap().as_ref() {
+            Some(x) => (x.position(), x.playback_status() == PlaybackStatus::Playing),
+            None => return Poll::Pending,
+        };
+
+        let generation = self.state.generation.load(Ordering::SeqCst);
+        if generation != self.generation {
+            self.generation = generation;
+            self.restart_ticks();
+            return Poll::Ready(Some(position));
+        }
+        if !playing {
+            self.sleep = None;
+            return Poll::Pending;
+        }
+
+        let next_tick = self.next_tick;
+        let sleep = self.sleep.get_or_insert_with(|| Box::pin(task::sleep(next_tick.saturating_duration_since(Instant::now()))));
+        if sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        // Ticks are scheduled from when they were due, so a late poll doesn't shift the rest
+        self.sleep = None;
+        self.next_tick = (self.next_tick + self.period).max(Instant::now());
+        return Poll::Ready(Some(position));
+    }
+}
@@ -0,0 +1,186 @@
+//! [`TrackListStream`] keeps a local copy of a player's tracklist up to date and yields it
+//! every time it changes.
+
+use std::{collections::HashMap, thread};
+
+use async_std::{channel::Receiver, task, stream::Stream};
+use mpris::{Event, Metadata, Player, TrackID};
+
+use crate::{connection::BusConfig, error::Error, handle::PlayerHandle, waker::{waking_channel, WakingReceiver, WakingSender}};
+
+/// A single entry of the tracklist.
+#[derive(Debug, Clone)]
+pub struct Track {
+    id: TrackID,
+    metadata: Option<Metadata>,
+}
+
+impl Track {
+    /// The id of the track.
+    pub fn id(&self) -> &TrackID {
+        &self.id
+    }
+
+    /// The metadata of the track. This is [`None`] while it is still being fetched from the
+    /// player, a later item of the stream will contain it.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+}
+
+/// Stream of the tracklist of a player, in order. A thread keeps a local mirror of the tracklist
+/// and applies `TrackAdded`, `TrackRemoved`, `TrackListReplaced` and `TrackMetadataChanged`
+/// events to it. The first item is the tracklist at the time the stream was created.
+///
+/// The stream ends when the player quits or when it does not support tracklists.
+#[derive(Debug, Clone)]
+pub struct TrackListStream {
+    handle: PlayerHandle,
+    reciever: WakingReceiver<Vec<Track>>,
+}
+
+impl TrackListStream {
+    /// Creates a new [`TrackListStream`] and a new thread to track changes. Streams made from
+    /// cloning share the same thread.
+    pub fn new(player: &Player) -> Self {
+        return TrackListStream::with_bus(player, BusConfig::Session);
+    }
+
+    /// Creates a new [`TrackListStream`] for a player on the given bus. `bus` must be the bus
+    /// that `player` was found on.
+    pub fn with_bus(player: &Player, bus: BusConfig) -> Self {
+        let (sender, reciever) = waking_channel();
+        let handle = PlayerHandle::with_bus(player, bus);
+        let listener_handle = handle.clone();
+        thread::spawn(move || TrackListStream::track_list_listener(listener_handle, sender));
+        return TrackListStream { handle, reciever };
+    }
+
+    fn track_list_listener(handle: PlayerHandle, sender: WakingSender<Vec<Track>>) {
+        TrackListStream::mirror_track_list(&handle, &sender);
+        sender.close();
+    }
+
+    fn mirror_track_list(handle: &PlayerHandle, sender: &WakingSender<Vec<Track>>) {
+        let player = match handle.find() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        if !player.supports_track_lists() {
+            return;
+        }
+        let mut events = match player.events() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let mut mirror = TrackListMirror::default();
+
+        let ids = match events.track_list() {
+            Some(x) => x.ids().to_vec(),
+            None => return,
+        };
+        if !mirror.update(&player, ids, sender) {
+            return;
+        }
+
+        while let Some(event) = events.next() {
+            match event {
+                Ok(Event::TrackAdded(_)) | Ok(Event::TrackListReplaced) => {},
+                Ok(Event::TrackRemoved(id)) => {
+                    mirror.metadata.remove(&id);
+                },
+                Ok(Event::TrackMetadataChanged { old_id, new_id }) => {
+                    // Fetched again below, the cached metadata is outdated
+                    mirror.metadata.remove(&old_id);
+                    mirror.metadata.remove(&new_id);
+                },
+                Ok(Event::PlayerShutDown) | Err(_) => return,
+                Ok(_) => continue,
+            }
+            let ids = match events.track_list() {
+                Some(x) => x.ids().to_vec(),
+                None => return,
+            };
+            if !mirror.update(&player, ids, sender) {
+                return;
+            }
+        }
+    }
+
+    /// Adds `uri` to the tracklist after the track `after`. If `set_as_current` is true, the new
+    /// track starts playing.
+    ///
+    /// See: [`Player::add_track`](mpris::Player::add_track)
+    pub async fn add_track(&self, uri: &str, after: &TrackID, set_as_current: bool) -> Result<(), Error> {
+        let uri = uri.to_string();
+        let after = after.clone();
+        self.handle.run(move |player| player.add_track(&uri, &after, set_as_current)).await
+    }
+
+    /// Removes a track from the tracklist.
+    ///
+    /// See: [`Player::remove_track`](mpris::Player::remove_track)
+    pub async fn remove_track(&self, id: &TrackID) -> Result<(), Error> {
+        let id = id.clone();
+        self.handle.run(move |player| player.remove_track(&id)).await
+    }
+
+    /// Skips to a track in the tracklist.
+    ///
+    /// See: [`Player::go_to`](mpris::Player::go_to)
+    pub async fn go_to(&self, id: &TrackID) -> Result<(), Error> {
+        let id = id.clone();
+        self.handle.run(move |player| player.go_to(&id)).await
+    }
+
+    /// Access to the reciever used to send the tracklist around.
+    pub fn get_reciever(&self) -> Receiver<Vec<Track>> {
+        self.reciever.reciever()
+    }
+}
+
+/// The state of the listener thread. Metadata is cached by id so that only new or changed tracks
+/// are fetched from the player.
+#[derive(Default)]
+struct TrackListMirror {
+    metadata: HashMap<TrackID, Metadata>,
+}
+
+impl TrackListMirror {
+    /// Sends the tracklist with the given order. If some metadata is missing, the tracklist is
+    /// sent once without it and again after it has been fetched. Returns false once nobody is
+    /// listening anymore.
+    fn update(&mut self, player: &Player, ids: Vec<TrackID>, sender: &WakingSender<Vec<Track>>) -> bool {
+        self.metadata.retain(|id, _| ids.contains(id));
+        let missing = ids.iter().filter(|x| !self.metadata.contains_key(x)).cloned().collect::<Vec<TrackID>>();
+
+        if !missing.is_empty() {
+            if !sender.send(self.tracks(&ids)) {
+                return false;
+            }
+
+            // Not every player returns the tracks in the order they were asked for
+            if let Ok(metadata) = player.get_tracks_metadata(&missing) {
+                for metadata in metadata {
+                    if let Some(id) = metadata.track_id() {
+                        self.metadata.insert(id, metadata);
+                    }
+                }
+            }
+        }
+
+        return sender.send(self.tracks(&ids));
+    }
+
+    fn tracks(&self, ids: &[TrackID]) -> Vec<Track> {
+        ids.iter().map(|id| Track { id: id.clone(), metadata: self.metadata.get(id).cloned() }).collect()
+    }
+}
+
+impl Stream for TrackListStream {
+    type Item = Vec<Track>;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Option<Self::Item>> {
+        self.reciever.poll_recv(cx)
+    }
+}
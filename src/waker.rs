@@ -0,0 +1,241 @@
+//! Waker bookkeeping shared by the streams in this crate. Every consumer owns a single
+//! [`WakerSlot`] that is overwritten on each poll, so repeated polling never queues up wakers.
+
+use std::{sync::{Arc, Mutex, Weak}, task::{Context, Poll, Waker}};
+
+use async_std::channel::{unbounded, Receiver, Sender, TryRecvError};
+
+/// Holds at most one [`Waker`]. Registering a new waker replaces the old one, similar to
+/// `AtomicWaker` from futures.
+#[derive(Debug, Default)]
+pub(crate) struct WakerSlot {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl WakerSlot {
+    /// Stores `waker` in the slot, unless the stored one would already wake the same task.
+    pub(crate) fn register(&self, waker: &Waker) {
+        let mut slot = self.waker.lock().unwrap();
+        match slot.as_ref() {
+            Some(old) if old.will_wake(waker) => {},
+            _ => *slot = Some(waker.clone()),
+        }
+    }
+
+    /// Wakes and clears the stored waker, if there is one.
+    pub(crate) fn wake(&self) {
+        let waker = self.waker.lock().unwrap().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// All of the [`WakerSlot`]s belonging to the consumers of one listener thread. Slots are held
+/// weakly so that dropped consumers are cleaned up on the next [`wake_all`](Self::wake_all).
+#[derive(Debug, Default, Clone)]
+pub(crate) struct WakerSet {
+    slots: Arc<Mutex<Vec<Weak<WakerSlot>>>>,
+}
+
+impl WakerSet {
+    /// Creates a new slot for a consumer and adds it to the set.
+    pub(crate) fn new_slot(&self) -> Arc<WakerSlot> {
+        let slot = Arc::new(WakerSlot::default());
+        self.slots.lock().unwrap().push(Arc::downgrade(&slot));
+        return slot;
+    }
+
+    /// Wakes every consumer that is still alive.
+    pub(crate) fn wake_all(&self) {
+        let mut alive = vec![];
+        self.slots.lock().unwrap().retain(|x| {
+            match x.upgrade() {
+                Some(slot) => {
+                    alive.push(slot);
+                    true
+                },
+                None => false,
+            }
+        });
+        // Wake outside of the lock, a woken task might want to create a new slot
+        for slot in alive {
+            slot.wake();
+        }
+    }
+}
+
+/// Creates the channel used between a listener thread and the streams reading from it. Sending
+/// wakes every stream, each of which only has a single waker registered.
+pub(crate) fn waking_channel<T>() -> (WakingSender<T>, WakingReceiver<T>) {
+    let (sender, reciever) = unbounded();
+    let wakers = WakerSet::default();
+    let waker = wakers.new_slot();
+    return (WakingSender { sender, wakers: wakers.clone() }, WakingReceiver { reciever, wakers, waker });
+}
+
+/// Sending half of [`waking_channel`], owned by the listener thread.
+#[derive(Debug)]
+pub(crate) struct WakingSender<T> {
+    sender: Sender<T>,
+    wakers: WakerSet,
+}
+
+impl<T> WakingSender<T> {
+    /// Sends `item` and wakes the streams. Returns false once every stream has been dropped.
+    pub(crate) fn send(&self, item: T) -> bool {
+        if self.sender.try_send(item).is_err() {
+            return false;
+        }
+        self.wakers.wake_all();
+        return true;
+    }
+
+    /// Whether every stream has been dropped, so there is no point in carrying on.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Ends the streams once they have read everything that was sent.
+    pub(crate) fn close(&self) {
+        self.sender.close();
+        self.wakers.wake_all();
+    }
+}
+
+impl<T> Clone for WakingSender<T> {
+    fn clone(&self) -> Self {
+        WakingSender { sender: self.sender.clone(), wakers: self.wakers.clone() }
+    }
+}
+
+/// Receiving half of [`waking_channel`]. Every clone gets its own [`WakerSlot`].
+#[derive(Debug)]
+pub(crate) struct WakingReceiver<T> {
+    reciever: Receiver<T>,
+    wakers: WakerSet,
+    waker: Arc<WakerSlot>,
+}
+
+impl<T> WakingReceiver<T> {
+    /// Polls for the next item, meant to be called from [`Stream::poll_next`](async_std::stream::Stream::poll_next).
+    pub(crate) fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        // Register before checking the channel, so an item sent in between still wakes us
+        self.waker.register(cx.waker());
+        match self.reciever.try_recv() {
+            Ok(item) => Poll::Ready(Some(item)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Closed) => Poll::Ready(None),
+        }
+    }
+
+    /// The underlying reciever.
+    pub(crate) fn reciever(&self) -> Receiver<T> {
+        self.reciever.clone()
+    }
+}
+
+impl<T> Clone for WakingReceiver<T> {
+    fn clone(&self) -> Self {
+        WakingReceiver {
+            reciever: self.reciever.clone(),
+            wakers: self.wakers.clone(),
+            waker: self.wakers.new_slot(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Condvar, task::Wake, thread, time::Duration};
+
+    use super::*;
+
+    /// Counts how often it was woken, and lets a thread wait for the next wake.
+    #[derive(Default)]
+    struct CountingWaker {
+        count: Mutex<usize>,
+        woken: Condvar,
+    }
+
+    impl CountingWaker {
+        fn count(&self) -> usize {
+            *self.count.lock().unwrap()
+        }
+
+        /// Waits until the count goes past `seen`, returns false on timeout.
+        fn wait_past(&self, seen: usize) -> bool {
+            let count = self.count.lock().unwrap();
+            let (count, _) = self.woken.wait_timeout_while(count, Duration::from_secs(5), |x| *x <= seen).unwrap();
+            return *count > seen;
+        }
+    }
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            *self.count.lock().unwrap() += 1;
+            self.woken.notify_all();
+        }
+    }
+
+    #[test]
+    fn every_send_wakes_the_receiver() {
+        const ITEMS: usize = 10_000;
+        let (sender, reciever) = waking_channel();
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let thread = thread::spawn(move || {
+            for x in 0..ITEMS {
+                assert!(sender.send(x));
+            }
+            sender.close();
+        });
+
+        let mut received = vec![];
+        loop {
+            // Read the count before polling, a wake landing after the poll must be seen
+            let seen = counter.count();
+            match reciever.poll_recv(&mut cx) {
+                Poll::Ready(Some(x)) => received.push(x),
+                Poll::Ready(None) => break,
+                Poll::Pending => assert!(counter.wait_past(seen), "a send did not wake the receiver"),
+            }
+        }
+        thread.join().unwrap();
+        assert_eq!(received, (0..ITEMS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn registering_replaces_the_waker() {
+        let slot = WakerSlot::default();
+        let first = Arc::new(CountingWaker::default());
+        let second = Arc::new(CountingWaker::default());
+        let first_waker = Waker::from(first.clone());
+
+        for _ in 0..100 {
+            slot.register(&first_waker);
+        }
+        slot.register(&Waker::from(second.clone()));
+        slot.wake();
+        slot.wake();
+        assert_eq!(first.count(), 0);
+        assert_eq!(second.count(), 1);
+    }
+
+    #[test]
+    fn polling_does_not_add_slots() {
+        let (sender, reciever) = waking_channel::<()>();
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        for _ in 0..100 {
+            assert!(reciever.poll_recv(&mut cx).is_pending());
+        }
+        assert_eq!(sender.wakers.slots.lock().unwrap().len(), 1);
+        sender.send(());
+        assert_eq!(counter.count(), 1);
+    }
+}